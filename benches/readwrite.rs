@@ -16,7 +16,7 @@ use cap_std::ambient_authority;
 use cap_std::fs::Dir;
 
 use serde::{Serialize, Deserialize};
-use std::rc::Rc;
+use std::sync::Arc;
 use std::ops::Range;
 
 const ELEMENTS: Range<i64> = 0..1000;
@@ -32,7 +32,7 @@ pub fn readwrite_benchmark(c: &mut Criterion) {
     let data_path = ".bench";
     let data = Dir::open_ambient_dir(data_path, ambient_authority()).unwrap();
 
-    let dd_rc = Rc::new(data);
+    let dd_rc = Arc::new(data);
 
     let mut db = Mudb::<BenchMsg>::open(
         dd_rc.clone(),
@@ -55,6 +55,22 @@ pub fn readwrite_benchmark(c: &mut Criterion) {
         });
     });
 
+    g.bench_function("insert_many", |b| {
+        b.iter(|| {
+            let items = ELEMENTS
+                .map(|oid| {
+                    let id = VersionedKey::new(IndexKey::Num(oid));
+                    let obj = BenchMsg {
+                        msg: format!("benchmark message {}", oid)
+                    };
+                    (Some(id), obj)
+                })
+                .collect();
+            let _ = db.insert_many(items).unwrap();
+            db.commit().unwrap();
+        });
+    });
+
     g.bench_function("update", |b| {
         b.iter(|| {
             for oid in ELEMENTS {
@@ -76,12 +92,33 @@ pub fn readwrite_benchmark(c: &mut Criterion) {
         });
     });
 
+    let mut buffered = Mudb::<BenchMsg>::builder(
+        dd_rc.clone(),
+        "db_rw_bench_b.ndjson"
+    )
+        .write_buffer_capacity(1024 * 1024)
+        .open()
+        .unwrap();
+
+    g.bench_function("insert_large_buffer", |b| {
+        b.iter(|| {
+            for oid in ELEMENTS {
+                let id = VersionedKey::new(IndexKey::Num(oid));
+                let obj = BenchMsg {
+                    msg: format!("benchmark message {}", oid)
+                };
+                let _ = buffered.insert(Some(id), obj).unwrap();
+            }
+            buffered.commit().unwrap();
+        });
+    });
+
     db = Mudb::<BenchMsg>::open(
         dd_rc.clone(),
         "db_rw_bench_c.ndjson"
     ).unwrap();
 
-    let _ = db.compact().unwrap();
+    db.compact().unwrap();
 
     g.bench_function("compact", |b| {
         b.iter(|| {
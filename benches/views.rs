@@ -6,7 +6,7 @@ use cap_std::fs::Dir;
 
 use serde::{Serialize, Deserialize};
 use kstring::KString;
-use std::rc::Rc;
+use std::sync::Arc;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 struct Facets {
@@ -38,14 +38,14 @@ pub fn view_benchmark(c: &mut Criterion) {
         ambient_authority()
     ).unwrap();
 
-    let dd_rc = Rc::new(data);
+    let dd_rc = Arc::new(data);
 
     let mut db = Mudb::<Facets>::open(
         dd_rc.clone(),
         "db_v.ndjson"
     ).unwrap();
 
-    let _ = db.add_view(&KString::from("facets"), Box::new(FacetIndexer {})).unwrap();
+    db.add_view(&KString::from("facets"), Box::new(FacetIndexer {})).unwrap();
 
     c.bench_function("insert_with_view", |b| {
         b.iter(|| {
@@ -58,15 +58,59 @@ pub fn view_benchmark(c: &mut Criterion) {
                 let _ = db.insert(None, obj).unwrap();
             }
 
-            let _ = db.build_views().unwrap();
+            db.build_views().unwrap();
             db.compact().unwrap();
         });
     });
 }
 
+#[cfg(feature = "rayon")]
+pub fn build_views_benchmark(c: &mut Criterion) {
+    let mut db = Mudb::<Facets>::in_memory();
+
+    for i in 0..25000 {
+        let obj = Facets {
+            a: format!("view+{}", i),
+            b: i,
+            c: (i % 3 == 0),
+        };
+        let _ = db.insert(None, obj).unwrap();
+    }
+
+    let mut group = c.benchmark_group("build_views");
+
+    group.bench_function("serial", |b| {
+        b.iter(|| {
+            for i in 0..4 {
+                db.add_view(&KString::from(format!("facets{}", i)), Box::new(FacetIndexer {})).unwrap();
+            }
+            db.build_views().unwrap();
+        });
+    });
+
+    group.bench_function("parallel", |b| {
+        b.iter(|| {
+            for i in 0..4 {
+                db.add_view(&KString::from(format!("facets{}", i)), Box::new(FacetIndexer {})).unwrap();
+            }
+            db.par_build_views().unwrap();
+        });
+    });
+
+    group.finish();
+}
+
+#[cfg(not(feature = "rayon"))]
+criterion_group!(
+    benches,
+    view_benchmark,
+);
+
+#[cfg(feature = "rayon")]
 criterion_group!(
     benches,
     view_benchmark,
+    build_views_benchmark,
 );
 
 criterion_main!(benches);
@@ -0,0 +1,67 @@
+use syn::{Data, DeriveInput, Error, Field, Fields, Ident, Result, Type};
+
+/// The named fields of a non-generic struct, which is all the derives
+/// support.
+pub(crate) fn named_fields<'i>(input: &'i DeriveInput, derive: &str) -> Result<Vec<&'i Field>> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            format!("#[derive({})] doesn't support generic types", derive)
+        ));
+    }
+
+    match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => Ok(fields.named.iter().collect()),
+            _ => Err(Error::new_spanned(
+                &input.ident,
+                format!("#[derive({})] needs a struct with named fields", derive)
+            )),
+        },
+        _ => Err(Error::new_spanned(
+            &input.ident,
+            format!("#[derive({})] only supports structs", derive)
+        )),
+    }
+}
+
+/// What kind of value a field holds, going by the last segment of its type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Scalar {
+    Str,
+    Int,
+    Bool,
+}
+
+pub(crate) fn scalar(ty: &Type) -> Option<Scalar> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+
+    let ident = &path.path.segments.last()?.ident;
+    let is = |names: &[&str]| names.iter().any(|name| ident == name);
+
+    if is(&["String", "KString"]) {
+        Some(Scalar::Str)
+    } else if is(&["i8", "i16", "i32", "i64", "isize", "u8", "u16", "u32", "u64", "usize"]) {
+        Some(Scalar::Int)
+    } else if is(&["bool"]) {
+        Some(Scalar::Bool)
+    } else {
+        None
+    }
+}
+
+/// `snake_case` to `CamelCase`, for building type names from field names.
+pub(crate) fn camel_case(ident: &Ident) -> String {
+    ident.to_string()
+        .trim_start_matches("r#")
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            chars.next()
+                .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
@@ -0,0 +1,96 @@
+use crate::fields::{camel_case, named_fields, scalar, Scalar};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Attribute, DeriveInput, Error, Field, Lit, Meta, NestedMeta, Result};
+
+// How an indexed field's value becomes an `IndexKey`.
+enum KeyAs {
+    Str,
+    Num,
+}
+
+pub(crate) fn derive(input: DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let mut indexers = vec![];
+
+    for field in named_fields(&input, "Indexer")? {
+        let Some(key_as) = key_as(field)? else {
+            continue;
+        };
+
+        let field_name = field.ident.as_ref().expect("named field");
+        let indexer = format_ident!("{}{}Indexer", name, camel_case(field_name));
+        let doc = format!("Indexes `{}` by its `{}` field.", name, field_name);
+
+        let key = match key_as {
+            KeyAs::Str => quote! {
+                ::mudb::IndexKey::Str(::std::string::ToString::to_string(&obj.#field_name).into())
+            },
+            KeyAs::Num => quote! {
+                ::mudb::IndexKey::Num(obj.#field_name as i64)
+            },
+        };
+
+        indexers.push(quote! {
+            #[doc = #doc]
+            #[derive(Debug, Clone, Copy, Default)]
+            #vis struct #indexer;
+
+            impl ::mudb::Indexer<#name> for #indexer {
+                fn index(&self, obj: &#name) -> ::std::vec::Vec<::mudb::IndexKey> {
+                    ::std::vec![#key]
+                }
+            }
+        });
+    }
+
+    Ok(quote! { #(#indexers)* })
+}
+
+// Reads a field's `#[index]` attribute, if it has one.
+fn key_as(field: &Field) -> Result<Option<KeyAs>> {
+    let Some(attr) = field.attrs.iter().find(|attr| attr.path.is_ident("index")) else {
+        return Ok(None);
+    };
+
+    match explicit_as(attr)? {
+        Some(key_as) => Ok(Some(key_as)),
+        None => match scalar(&field.ty) {
+            Some(Scalar::Str) => Ok(Some(KeyAs::Str)),
+            Some(Scalar::Int | Scalar::Bool) => Ok(Some(KeyAs::Num)),
+            None => Err(Error::new_spanned(
+                &field.ty,
+                "can't tell how to index this type; add `as = \"str\"` or `as = \"num\"`"
+            )),
+        },
+    }
+}
+
+// The `as` in `#[index(as = "...")]`; `None` for a bare `#[index]`.
+fn explicit_as(attr: &Attribute) -> Result<Option<KeyAs>> {
+    let list = match attr.parse_meta()? {
+        Meta::Path(_) => return Ok(None),
+        Meta::List(list) => list,
+        meta @ Meta::NameValue(_) => {
+            return Err(Error::new_spanned(meta, "expected #[index] or #[index(as = \"...\")]"));
+        },
+    };
+
+    let mut key_as = None;
+
+    for nested in &list.nested {
+        match nested {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("as") => {
+                key_as = Some(match &nv.lit {
+                    Lit::Str(lit) if lit.value() == "str" => KeyAs::Str,
+                    Lit::Str(lit) if lit.value() == "num" => KeyAs::Num,
+                    lit => return Err(Error::new_spanned(lit, "expected \"str\" or \"num\"")),
+                });
+            },
+            other => return Err(Error::new_spanned(other, "unknown #[index] option")),
+        }
+    }
+
+    Ok(key_as)
+}
@@ -0,0 +1,36 @@
+//! Derive macros for `mudb`, re-exported from it behind the `derive` feature.
+
+use proc_macro::TokenStream;
+use syn::{parse_macro_input, DeriveInput};
+
+mod fields;
+mod indexer;
+mod queryable;
+
+/// Generates an `Indexer` for each field marked `#[index]`, named after the
+/// struct and field: `#[index] kind` on `Msg` gives `MsgKindIndexer`.
+///
+/// `String` fields become `IndexKey::Str`, and integer and `bool` fields
+/// `IndexKey::Num`. `#[index(as = "str")]` indexes any `Display` field by
+/// its string form, and `#[index(as = "num")]` casts a field with `as i64`.
+#[proc_macro_derive(Indexer, attributes(index))]
+pub fn derive_indexer(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    indexer::derive(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
+
+/// Generates `query()`, returning a builder with a `<field>_eq` method for
+/// every `String`, integer and `bool` field, plus `<field>_contains` for
+/// `String` fields. Each makes a query ready to hand to `find`:
+/// `Msg::query().kind_eq(1)`. Fields of other types are left out.
+#[proc_macro_derive(Queryable)]
+pub fn derive_queryable(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    queryable::derive(input)
+        .unwrap_or_else(|err| err.to_compile_error())
+        .into()
+}
@@ -0,0 +1,66 @@
+use crate::fields::{named_fields, scalar, Scalar};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{DeriveInput, Result};
+
+pub(crate) fn derive(input: DeriveInput) -> Result<TokenStream> {
+    let name = &input.ident;
+    let vis = &input.vis;
+    let builder = format_ident!("{}Queries", name);
+    let builder_doc = format!("Builds queries on the fields of `{}`.", name);
+    let mut methods = vec![];
+
+    for field in named_fields(&input, "Queryable")? {
+        let Some(kind) = scalar(&field.ty) else {
+            continue;
+        };
+
+        let field_name = field.ident.as_ref().expect("named field");
+        let ty = &field.ty;
+
+        let eq = format_ident!("{}_eq", field_name);
+        let eq_doc = format!("Matches docs whose `{}` equals `value`.", field_name);
+
+        // Taking numbers as-is lets a bare literal like `1` infer its type.
+        let (param, value) = match kind {
+            Scalar::Str => (quote!(impl ::std::convert::Into<#ty>), quote!(value.into())),
+            Scalar::Int | Scalar::Bool => (quote!(#ty), quote!(value)),
+        };
+
+        methods.push(quote! {
+            #[doc = #eq_doc]
+            #vis fn #eq(&self, value: #param) -> ::mudb::FieldEq<#name, #ty> {
+                ::mudb::FieldEq::new(#value, |obj| &obj.#field_name)
+            }
+        });
+
+        if kind == Scalar::Str {
+            let contains = format_ident!("{}_contains", field_name);
+            let contains_doc = format!("Matches docs whose `{}` contains `needle`.", field_name);
+
+            methods.push(quote! {
+                #[doc = #contains_doc]
+                #vis fn #contains(&self, needle: &str) -> ::mudb::Contains<#name> {
+                    ::mudb::Contains::new(needle, |obj| ::std::option::Option::Some(obj.#field_name.as_str()))
+                }
+            });
+        }
+    }
+
+    Ok(quote! {
+        #[doc = #builder_doc]
+        #[derive(Debug, Clone, Copy, Default)]
+        #vis struct #builder;
+
+        impl #name {
+            /// Starts a query on this type's fields.
+            #vis fn query() -> #builder {
+                #builder
+            }
+        }
+
+        impl #builder {
+            #(#methods)*
+        }
+    })
+}
@@ -0,0 +1,144 @@
+use cap_std::ambient_authority;
+use cap_std::fs::Dir;
+use clap::{Parser, Subcommand};
+use kstring::KString;
+use mudb::{Codec, CompactMode, FnQuery, IndexKey, JsonLines, JsonMudb, MudbError, Result};
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+/// Maintenance and inspection for mudb data files.
+#[derive(Debug, Parser)]
+#[clap(name = "mudb-server")]
+struct Cli {
+    /// Directory holding the data files.
+    #[clap(long, default_value = ".")]
+    data_dir: String,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Check that every record in `file` deserializes, printing how many
+    /// did and failing at the first that didn't.
+    Check { file: String },
+    /// Print every live doc in `file` as JSON, one per line.
+    Dump { file: String },
+    /// Rewrite `file` without superseded versions, and print how many bytes
+    /// that reclaimed.
+    Compact {
+        file: String,
+        /// Drop deleted docs entirely, rather than keeping their tombstones.
+        #[clap(long)]
+        drop_tombstones: bool,
+    },
+    /// Read queries from stdin, one per line, and print their results as
+    /// JSON: `get <id>`, `count`, `find <substring>`, `recent <n>`.
+    Repl { file: String },
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("mudb-server: {}", err);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn run(cli: Cli) -> Result<()> {
+    let data_dir = Arc::new(Dir::open_ambient_dir(&cli.data_dir, ambient_authority())?);
+
+    match cli.command {
+        Command::Check { file } => {
+            let reader = BufReader::new(data_dir.open(&file)?);
+            let mut valid = 0;
+            let mut corrupt = 0;
+            let mut first_failure = None;
+
+            for doc in Codec::<Value>::decode(&JsonLines, Box::new(reader)) {
+                match doc {
+                    Ok(_) => valid += 1,
+                    Err(MudbError::Deserialize { line, .. }) => {
+                        corrupt += 1;
+                        first_failure.get_or_insert(line);
+                    },
+                    Err(err) => return Err(err),
+                }
+            }
+
+            println!("{} valid records", valid);
+
+            if let Some(line) = first_failure {
+                return Err(MudbError::Invalid(format!(
+                    "{} corrupt records, the first at line {}", corrupt, line
+                )));
+            }
+        },
+        Command::Dump { file } => {
+            let db = JsonMudb::open_read_only(data_dir, &file)?;
+            db.export(io::stdout().lock())?;
+        },
+        Command::Compact { file, drop_tombstones } => {
+            // Opening for writing would create a missing file.
+            data_dir.metadata(&file)?;
+
+            let mode = if drop_tombstones {
+                CompactMode::DropTombstones
+            } else {
+                CompactMode::KeepTombstones
+            };
+
+            let mut db = JsonMudb::open(data_dir, &file)?;
+            let stats = db.compact_with(mode)?;
+            println!("reclaimed {} bytes", stats.bytes_before.saturating_sub(stats.bytes_after));
+        },
+        Command::Repl { file } => {
+            let db = JsonMudb::open_read_only(data_dir, &file)?;
+            repl(&db, io::stdin().lock(), io::stdout().lock())?;
+        },
+    }
+
+    Ok(())
+}
+
+fn repl(db: &JsonMudb, input: impl BufRead, mut out: impl Write) -> Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        let (command, arg) = line.trim().split_once(' ').unwrap_or((line.trim(), ""));
+
+        let result = match (command, arg.trim()) {
+            ("", _) => continue,
+            ("quit", _) | ("exit", _) => break,
+            ("get", id) if !id.is_empty() => {
+                // Ids that parse as integers are numeric keys.
+                let id = id.parse()
+                    .map(IndexKey::Num)
+                    .unwrap_or_else(|_| IndexKey::Str(KString::from_ref(id)));
+                Ok(db.get(&id).and_then(|doc| doc.obj().cloned()).unwrap_or(Value::Null))
+            },
+            ("count", "") => Ok(Value::from(db.len())),
+            ("find", needle) if !needle.is_empty() => {
+                let matches = FnQuery(|obj: &Value| obj.to_string().contains(needle));
+                Ok(Value::from(db.find(&matches)))
+            },
+            ("recent", n) => n.parse()
+                .map(|n| Value::from(db.recent(n)))
+                .map_err(|_| format!("not a count: {:?}", n)),
+            _ => Err(format!("unknown command: {:?}", line)),
+        };
+
+        match result {
+            Ok(value) => writeln!(out, "{}", value)?,
+            Err(msg) => eprintln!("{}", msg),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,40 @@
+// Serde helpers for binary payloads: base64 text in human-readable formats
+// like JSON, raw bytes everywhere else.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub(crate) fn serialize<S: Serializer>(
+    blob: &Option<Vec<u8>>,
+    serializer: S
+) -> Result<S::Ok, S::Error> {
+    match blob {
+        Some(bytes) if serializer.is_human_readable() => {
+            serializer.serialize_some(&base64::encode(bytes))
+        },
+        Some(bytes) => serializer.serialize_some(serde_bytes::Bytes::new(bytes)),
+        None => serializer.serialize_none(),
+    }
+}
+
+// Serializes a blob field through `serialize`, for hand-written impls.
+pub(crate) struct Blob<'a>(pub(crate) &'a Option<Vec<u8>>);
+
+impl Serialize for Blob<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(self.0, serializer)
+    }
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D
+) -> Result<Option<Vec<u8>>, D::Error> {
+    if deserializer.is_human_readable() {
+        Option::<String>::deserialize(deserializer)?
+            .map(|text| base64::decode(text).map_err(D::Error::custom))
+            .transpose()
+    } else {
+        Ok(Option::<serde_bytes::ByteBuf>::deserialize(deserializer)?
+            .map(|buf| buf.into_vec()))
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    Codec, Compression, ConflictResolver, CorruptPolicy, DocType, Durability, Format, IdGenerator,
+    Mudb, MudbOptions, Result,
+};
+use cap_std::fs::Dir;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Fluent setup for `Mudb::open_with_options`, starting from the defaults
+/// and set through `Mudb::builder`.
+#[derive(Debug)]
+pub struct MudbBuilder<T: DocType> {
+    data_dir: Arc<Dir>,
+    filename: String,
+    options: MudbOptions<T>,
+}
+
+impl <T: DocType> MudbBuilder<T> {
+    pub fn new(data_dir: Arc<Dir>, filename: &str) -> Self {
+        Self {
+            data_dir,
+            filename: filename.to_string(),
+            options: MudbOptions::default(),
+        }
+    }
+
+    pub fn codec(mut self, codec: Box<dyn Codec<T>>) -> Self {
+        self.options.codec = codec;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.options.compression = compression;
+        self
+    }
+
+    pub fn format(mut self, format: Format) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn on_corrupt(mut self, on_corrupt: CorruptPolicy) -> Self {
+        self.options.on_corrupt = on_corrupt;
+        self
+    }
+
+    pub fn repair_on_open(mut self, repair_on_open: bool) -> Self {
+        self.options.repair_on_open = repair_on_open;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+
+    pub fn live_views(mut self, live_views: bool) -> Self {
+        self.options.live_views = live_views;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.options.ttl = Some(ttl);
+        self
+    }
+
+    pub fn autocommit_threshold(mut self, threshold: usize) -> Self {
+        self.options.autocommit_threshold = Some(threshold);
+        self
+    }
+
+    pub fn write_through(mut self, write_through: bool) -> Self {
+        self.options.write_through = write_through;
+        self
+    }
+
+    pub fn auto_compact_ratio(mut self, ratio: f64) -> Self {
+        self.options.auto_compact_ratio = Some(ratio);
+        self
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.options.durability = durability;
+        self
+    }
+
+    pub fn keep_latest_only(mut self, keep_latest_only: bool) -> Self {
+        self.options.keep_latest_only = keep_latest_only;
+        self
+    }
+
+    pub fn retain_deleted(mut self, retain_deleted: bool) -> Self {
+        self.options.retain_deleted = retain_deleted;
+        self
+    }
+
+    pub fn id_generator(mut self, id_generator: Box<dyn IdGenerator>) -> Self {
+        self.options.id_generator = id_generator;
+        self
+    }
+
+    pub fn conflict_resolver(mut self, resolver: Box<dyn ConflictResolver<T>>) -> Self {
+        self.options.conflict_resolver = resolver;
+        self
+    }
+
+    pub fn write_buffer_capacity(mut self, capacity: usize) -> Self {
+        self.options.write_buffer_capacity = capacity;
+        self
+    }
+
+    pub fn open(self) -> Result<Mudb<T>> {
+        Mudb::open_with_options(self.data_dir, &self.filename, self.options)
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{Doc, DocType, MudbError, Result};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+pub trait Codec<T: DocType>: fmt::Debug + Send + Sync {
+    fn encode(&self, doc: &Doc<T>) -> Result<Vec<u8>>;
+
+    /// Writes the encoding of `doc` straight to `out`. Override this to skip
+    /// the intermediate buffer `encode` allocates.
+    fn encode_into(&self, doc: &Doc<T>, out: &mut dyn Write) -> Result<()> {
+        out.write_all(&self.encode(doc)?)?;
+        Ok(())
+    }
+
+    fn decode<'r>(
+        &self,
+        reader: Box<dyn BufRead + 'r>
+    ) -> Box<dyn Iterator<Item = Result<Doc<T>>> + 'r>
+    where
+        T: 'r;
+
+    /// Whether `decode` carries on past a bad record, as a line-delimited
+    /// encoding can. If not, nothing after one can be read back.
+    fn resyncs(&self) -> bool {
+        false
+    }
+}
+
+// Lets a db hand its own codec to another one, as `fork` does.
+impl <T: DocType> Codec<T> for Arc<dyn Codec<T>> {
+    fn encode(&self, doc: &Doc<T>) -> Result<Vec<u8>> {
+        (**self).encode(doc)
+    }
+
+    fn encode_into(&self, doc: &Doc<T>, out: &mut dyn Write) -> Result<()> {
+        (**self).encode_into(doc, out)
+    }
+
+    fn decode<'r>(
+        &self,
+        reader: Box<dyn BufRead + 'r>
+    ) -> Box<dyn Iterator<Item = Result<Doc<T>>> + 'r>
+    where
+        T: 'r
+    {
+        (**self).decode(reader)
+    }
+
+    fn resyncs(&self) -> bool {
+        (**self).resyncs()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonLines;
+
+impl <T: DocType> Codec<T> for JsonLines {
+    fn encode(&self, doc: &Doc<T>) -> Result<Vec<u8>> {
+        let mut buf = serde_json::to_vec(doc)
+            .map_err(|err| MudbError::Serialize(err.into()))?;
+        buf.push(b'\n');
+        Ok(buf)
+    }
+
+    fn encode_into(&self, doc: &Doc<T>, mut out: &mut dyn Write) -> Result<()> {
+        serde_json::to_writer(&mut out, doc)
+            .map_err(|err| MudbError::Serialize(err.into()))?;
+        out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn decode<'r>(
+        &self,
+        mut reader: Box<dyn BufRead + 'r>
+    ) -> Box<dyn Iterator<Item = Result<Doc<T>>> + 'r>
+    where
+        T: 'r
+    {
+        let mut line = 0;
+        let mut buf = vec![];
+
+        Box::new(std::iter::from_fn(move || loop {
+            buf.clear();
+            line += 1;
+
+            match reader.read_until(b'\n', &mut buf) {
+                Ok(0) => return None,
+                Ok(_) if buf.trim_ascii().is_empty() => continue,
+                Ok(_) => {
+                    return Some(serde_json::from_slice(&buf).map_err(|err| {
+                        MudbError::Deserialize {
+                            line,
+                            raw: Some(String::from_utf8_lossy(&buf).into_owned()),
+                            source: err.into(),
+                        }
+                    }))
+                },
+                Err(err) => return Some(Err(err.into())),
+            }
+        }))
+    }
+
+    fn resyncs(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl <T: DocType> Codec<T> for Bincode {
+    fn encode(&self, doc: &Doc<T>) -> Result<Vec<u8>> {
+        bincode::serialize(doc)
+            .map_err(|err| MudbError::Serialize(err))
+    }
+
+    fn encode_into(&self, doc: &Doc<T>, out: &mut dyn Write) -> Result<()> {
+        bincode::serialize_into(out, doc)
+            .map_err(|err| MudbError::Serialize(err))
+    }
+
+    fn decode<'r>(
+        &self,
+        mut reader: Box<dyn BufRead + 'r>
+    ) -> Box<dyn Iterator<Item = Result<Doc<T>>> + 'r>
+    where
+        T: 'r
+    {
+        // Records aren't delimited, so there's no way to resync after a bad
+        // one: stop at the first error. Without lines, errors report the
+        // 1-based record number instead.
+        let mut failed = false;
+        let mut record = 0;
+
+        Box::new(std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+
+            record += 1;
+
+            let next = match reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(_) => bincode::deserialize_from(&mut reader)
+                    .map_err(|err| MudbError::Deserialize {
+                        line: record,
+                        raw: None,
+                        source: err,
+                    }),
+                Err(err) => Err(err.into()),
+            };
+
+            failed = next.is_err();
+            Some(next)
+        }))
+    }
+}
@@ -0,0 +1,97 @@
+use crate::Result;
+use std::io::{self, BufRead, Write};
+
+#[cfg(feature = "gzip")]
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use std::io::BufReader;
+
+/// Compression applied to the data file.
+///
+/// Commits append to the file, so every commit (and every compaction) is
+/// written as its own self-contained compressed frame. Reading decodes the
+/// concatenated frames back into one stream, which both gzip and zstd
+/// support natively.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    pub(crate) fn encoder<W: Write>(&self, inner: W) -> Result<Encoder<W>> {
+        Ok(match self {
+            Compression::None => Encoder::Plain(inner),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Encoder::Gzip(
+                GzEncoder::new(inner, flate2::Compression::default())
+            ),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(inner, 0)?),
+        })
+    }
+
+    pub(crate) fn decoder<'r, R: BufRead + 'r>(
+        &self,
+        inner: R
+    ) -> Result<Box<dyn BufRead + 'r>> {
+        Ok(match self {
+            Compression::None => Box::new(inner),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(
+                BufReader::new(MultiGzDecoder::new(inner))
+            ),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(
+                BufReader::new(zstd::Decoder::with_buffer(inner)?)
+            ),
+        })
+    }
+}
+
+pub(crate) enum Encoder<W: Write> {
+    Plain(W),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl <W: Write> Encoder<W> {
+    /// Ends the current frame, handing back the underlying writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Plain(inner) => Ok(inner),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(enc) => enc.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(enc) => enc.finish(),
+        }
+    }
+}
+
+impl <W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(inner) => inner.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(enc) => enc.write(buf),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(enc) => enc.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(inner) => inner.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(enc) => enc.flush(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(enc) => enc.flush(),
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use crate::{IndexKey, VersionedKey};
+use kstring::KString;
+use std::error::Error;
+use std::io;
+use thiserror::Error;
+
+pub type Result<T> = std::result::Result<T, MudbError>;
+
+type Source = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum MudbError {
+    #[error("version conflict: expected {expected:?}, found {found:?}")]
+    VersionConflict {
+        expected: VersionedKey,
+        found: VersionedKey,
+    },
+
+    #[error("failed to deserialize record at line {line}")]
+    Deserialize {
+        line: usize,
+        raw: Option<String>,
+        #[source]
+        source: Source,
+    },
+
+    #[error("failed to serialize record")]
+    Serialize(#[source] Source),
+
+    #[error("view {view} already has a doc for key {key:?}")]
+    UniqueViolation {
+        view: KString,
+        key: IndexKey,
+    },
+
+    #[error("invalid doc: {0}")]
+    Invalid(String),
+
+    #[error("db was opened read-only")]
+    ReadOnly,
+
+    #[error("db is already open for writing (lock file {0} exists)")]
+    Locked(String),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
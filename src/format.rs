@@ -0,0 +1,79 @@
+use crate::{Codec, Doc, DocType, MudbError, Result};
+use serde::Deserialize;
+use std::io::{BufRead, Write};
+
+/// How records are laid out in the data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Records one after another in the db's codec: a JSON object per line
+    /// by default. Commits append to the file.
+    #[default]
+    Ndjson,
+    /// The whole file is a single JSON array of records, so it's one valid
+    /// JSON document. Records are always JSON here, whatever the codec.
+    ///
+    /// An array can't be appended to, so every commit rewrites the whole
+    /// file as `compact` does (dropping superseded versions): a commit costs
+    /// time in proportion to the size of the db, not of the change. There's
+    /// no skipping past a corrupt record either, so one fails the open
+    /// whatever `on_corrupt` says.
+    JsonArray,
+}
+
+impl Format {
+    pub(crate) fn decode<'r, T: DocType + 'r>(
+        &self,
+        codec: &dyn Codec<T>,
+        reader: Box<dyn BufRead + 'r>
+    ) -> Box<dyn Iterator<Item = Result<Doc<T>>> + 'r> {
+        match self {
+            Format::Ndjson => codec.decode(reader),
+            Format::JsonArray => {
+                // The array is parsed straight from the reader, without
+                // reading the file into memory first.
+                let mut de = serde_json::Deserializer::from_reader(reader);
+                let docs = Vec::<Doc<T>>::deserialize(&mut de)
+                    .and_then(|docs| de.end().map(|_| docs))
+                    .map_err(|err| MudbError::Deserialize {
+                        line: err.line(),
+                        raw: None,
+                        source: err.into(),
+                    });
+
+                match docs {
+                    Ok(docs) => Box::new(docs.into_iter().map(Ok)),
+                    Err(err) => Box::new(std::iter::once(Err(err))),
+                }
+            },
+        }
+    }
+
+    // Writes `docs` out as the complete contents of a file.
+    pub(crate) fn write_all<'d, T: DocType + 'd>(
+        &self,
+        codec: &dyn Codec<T>,
+        docs: impl Iterator<Item = &'d Doc<T>>,
+        mut out: &mut dyn Write
+    ) -> Result<()> {
+        match self {
+            Format::Ndjson => {
+                for doc in docs {
+                    codec.encode_into(doc, out)?;
+                }
+            },
+            Format::JsonArray => {
+                out.write_all(b"[")?;
+
+                for (n, doc) in docs.enumerate() {
+                    out.write_all(if n == 0 { b"\n" } else { b",\n" })?;
+                    serde_json::to_writer(&mut out, doc)
+                        .map_err(|err| MudbError::Serialize(err.into()))?;
+                }
+
+                out.write_all(b"\n]\n")?;
+            },
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,44 @@
+use crate::IndexKey;
+use kstring::KString;
+use rusty_ulid::generate_ulid_string;
+use std::fmt;
+
+/// Picks the id for docs inserted without a key.
+///
+/// `observe` is called with every id loaded on open and every id stored
+/// afterwards, so generators that depend on the existing keys can keep up.
+pub trait IdGenerator: fmt::Debug + Send + Sync {
+    fn generate(&mut self) -> IndexKey;
+
+    fn observe(&mut self, _id: &IndexKey) {}
+}
+
+/// The default generator: a fresh ULID string per doc.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ulid;
+
+impl IdGenerator for Ulid {
+    fn generate(&mut self) -> IndexKey {
+        IndexKey::Str(KString::from(generate_ulid_string()))
+    }
+}
+
+/// Sequential numeric ids: the next `IndexKey::Num` above the largest one
+/// seen so far.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AutoIncrement {
+    last: i64,
+}
+
+impl IdGenerator for AutoIncrement {
+    fn generate(&mut self) -> IndexKey {
+        self.last += 1;
+        IndexKey::Num(self.last)
+    }
+
+    fn observe(&mut self, id: &IndexKey) {
+        if let IndexKey::Num(num) = id {
+            self.last = self.last.max(*num);
+        }
+    }
+}
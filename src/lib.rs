@@ -1,18 +1,63 @@
-use anyhow::Result;
 use cap_std::fs::{Dir, File, OpenOptions};
 use cap_tempfile::TempFile;
-use rusty_ulid::generate_ulid_string;
 use kstring::KString;
 use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashSet};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use im::ordmap::{DiffItem, OrdMap};
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
-use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::ops::{BitAnd, BitOr, Not};
-use std::rc::Rc;
-use std::cell::RefCell;
-use tracing::{error, instrument};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::ops::{BitAnd, BitOr, Bound, Not, RangeBounds, RangeInclusive};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, instrument, warn, Span};
+
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+
+// Lets code generated by `mudb-derive`, which names `::mudb`, compile in
+// this crate's own tests.
+#[cfg(all(test, feature = "derive"))]
+extern crate self as mudb;
+
+mod blob;
+mod builder;
+mod codec;
+mod compression;
+mod error;
+mod format;
+mod id;
+mod lock;
+mod queries;
+mod shared;
+mod snapshot;
+
+#[cfg(feature = "bincode")]
+pub use codec::Bincode;
+pub use builder::MudbBuilder;
+pub use codec::{Codec, JsonLines};
+pub use compression::Compression;
+pub use error::{MudbError, Result};
+pub use format::Format;
+pub use id::{AutoIncrement, IdGenerator, Ulid};
+#[cfg(feature = "derive")]
+pub use mudb_derive::{Indexer, Queryable};
+pub use queries::{Contains, ContainsIgnoreCase, FieldEq, InQuery, RangeQuery, TextField};
+#[cfg(feature = "regex")]
+pub use queries::RegexQuery;
+pub use shared::SyncMudb;
+pub use snapshot::ReadSnapshot;
+use lock::LockFile;
+pub use ordered_float::OrderedFloat;
+
+fn views_filename(filename: &str) -> String {
+    format!("{}.views", filename)
+}
 
 fn default_open_options() -> OpenOptions {
     let mut options = OpenOptions::new();
@@ -30,8 +75,6 @@ pub enum Flag {
 }
 
 #[derive(
-    Serialize,
-    Deserialize,
     PartialEq,
     Eq,
     Debug,
@@ -40,10 +83,103 @@ pub enum Flag {
     PartialOrd,
     Hash,
 )]
-#[serde(untagged)]
 pub enum IndexKey {
     Str(KString),
     Num(i64),
+    Float(OrderedFloat<f64>),
+    /// A composite key, ordered element by element, so every key starting
+    /// with the same elements sorts together: a view keyed on
+    /// `(kind, time)` can scan one kind with a range from
+    /// `Tuple(vec![Num(1)])` up to `Tuple(vec![Num(2)])`.
+    Tuple(Vec<IndexKey>),
+}
+
+// Keys are written untagged (a bare string or number, or an array for a
+// tuple) in human-readable formats, but binary formats can't tell the
+// variants apart without a tag. Untagged floats always carry a decimal point
+// or exponent, which keeps them from reading back as `Num`. NaN and the
+// infinities have no JSON number, so they're written as `{"Float": "NaN"}`,
+// `{"Float": "inf"}` or `{"Float": "-inf"}` instead.
+impl Serialize for IndexKey {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let human = serializer.is_human_readable();
+
+        match self {
+            IndexKey::Str(s) if human => s.serialize(serializer),
+            IndexKey::Num(n) if human => n.serialize(serializer),
+            IndexKey::Float(f) if human && !f.is_finite() => {
+                serializer.serialize_newtype_variant("IndexKey", 2, "Float", &f.to_string())
+            },
+            IndexKey::Float(f) if human => f.0.serialize(serializer),
+            IndexKey::Tuple(keys) if human => keys.serialize(serializer),
+            IndexKey::Str(s) => {
+                serializer.serialize_newtype_variant("IndexKey", 0, "Str", s)
+            },
+            IndexKey::Num(n) => {
+                serializer.serialize_newtype_variant("IndexKey", 1, "Num", n)
+            },
+            IndexKey::Float(f) => {
+                serializer.serialize_newtype_variant("IndexKey", 2, "Float", &f.0)
+            },
+            IndexKey::Tuple(keys) => {
+                serializer.serialize_newtype_variant("IndexKey", 3, "Tuple", keys)
+            },
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UntaggedKey {
+    Str(KString),
+    Num(i64),
+    Float(f64),
+    Tuple(Vec<IndexKey>),
+    NonFinite(NonFiniteKey),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "IndexKey")]
+enum NonFiniteKey {
+    Float(KString),
+}
+
+#[derive(Deserialize)]
+#[serde(rename = "IndexKey")]
+enum TaggedKey {
+    Str(KString),
+    Num(i64),
+    Float(f64),
+    Tuple(Vec<IndexKey>),
+}
+
+impl <'de> Deserialize<'de> for IndexKey {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D
+    ) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            Ok(match UntaggedKey::deserialize(deserializer)? {
+                UntaggedKey::Str(s) => IndexKey::Str(s),
+                UntaggedKey::Num(n) => IndexKey::Num(n),
+                UntaggedKey::Float(f) => IndexKey::Float(OrderedFloat(f)),
+                UntaggedKey::Tuple(keys) => IndexKey::Tuple(keys),
+                UntaggedKey::NonFinite(NonFiniteKey::Float(f)) => {
+                    let f = f.parse::<f64>().map_err(serde::de::Error::custom)?;
+                    IndexKey::Float(OrderedFloat(f))
+                },
+            })
+        } else {
+            Ok(match TaggedKey::deserialize(deserializer)? {
+                TaggedKey::Str(s) => IndexKey::Str(s),
+                TaggedKey::Num(n) => IndexKey::Num(n),
+                TaggedKey::Float(f) => IndexKey::Float(OrderedFloat(f)),
+                TaggedKey::Tuple(keys) => IndexKey::Tuple(keys),
+            })
+        }
+    }
 }
 
 #[derive(
@@ -70,10 +206,20 @@ impl VersionedKey {
         }
     }
 
+    /// A key for version `ver` of `id`, as for `get_version` or a
+    /// compare-and-swap through `insert`.
+    pub fn at(id: IndexKey, ver: u64) -> Self {
+        Self { id, ver }
+    }
+
     pub fn id(&self) -> IndexKey {
         self.id.clone()
     }
 
+    pub fn ver(&self) -> u64 {
+        self.ver
+    }
+
     pub fn incr(&self) -> Self {
         Self {
             id: self.id.clone(),
@@ -82,33 +228,173 @@ impl VersionedKey {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct Doc<T: Clone + fmt::Debug + Eq> {
     key: VersionedKey,
     flags: HashSet<Flag>,
     obj: Option<T>,
+    #[serde(default, deserialize_with = "blob::deserialize")]
+    blob: Option<Vec<u8>>,
+    #[serde(default)]
+    created_at: Option<u64>,
+    #[serde(default)]
+    updated_at: Option<u64>,
+    // The object a tombstone replaced, under `retain_deleted`. Missing from
+    // older records, and left out of human-readable ones when empty.
+    #[serde(default = "Option::default")]
+    retained: Option<T>,
+}
+
+// Binary formats read fields by position, so only human-readable ones can
+// leave out an empty `retained`; serde's `skip_serializing_if` can't tell
+// them apart.
+impl<T: Serialize + Clone + fmt::Debug + Eq> Serialize for Doc<T> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S
+    ) -> std::result::Result<S::Ok, S::Error> {
+        let skip_retained = serializer.is_human_readable() && self.retained.is_none();
+        let len = if skip_retained { 6 } else { 7 };
+
+        let mut doc = serializer.serialize_struct("Doc", len)?;
+        doc.serialize_field("key", &self.key)?;
+        doc.serialize_field("flags", &self.flags)?;
+        doc.serialize_field("obj", &self.obj)?;
+        doc.serialize_field("blob", &blob::Blob(&self.blob))?;
+        doc.serialize_field("created_at", &self.created_at)?;
+        doc.serialize_field("updated_at", &self.updated_at)?;
+        if skip_retained {
+            doc.skip_field("retained")?;
+        } else {
+            doc.serialize_field("retained", &self.retained)?;
+        }
+        doc.end()
+    }
 }
 
 impl<T: Serialize + DeserializeOwned + Clone + fmt::Debug + Eq> Doc<T> {
     pub fn new(key: VersionedKey, obj: Option<T>) -> Self {
+        Self::with_flags(key, obj, HashSet::new())
+    }
+
+    /// Like `new`, but with `flags` already set, e.g. for a tombstone
+    /// (`Flag::Deleted`) headed for `apply_changes`.
+    pub fn with_flags(key: VersionedKey, obj: Option<T>, flags: HashSet<Flag>) -> Self {
         Self {
             key,
             obj,
-            flags: HashSet::new(),
+            flags,
+            blob: None,
+            created_at: None,
+            updated_at: None,
+            retained: None,
         }
     }
 
     pub fn has_flag(&self, flag: &Flag) -> bool {
         self.flags.contains(flag)
     }
+
+    /// The id and version this doc is stored under.
+    ///
+    /// ```
+    /// # use cap_std::ambient_authority;
+    /// # use cap_tempfile::TempDir;
+    /// # use mudb::{DocType, Mudb};
+    /// # use serde::{Deserialize, Serialize};
+    /// # use std::sync::Arc;
+    /// #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+    /// struct Note(String);
+    ///
+    /// impl DocType for Note {}
+    ///
+    /// # fn main() -> anyhow::Result<()> {
+    /// # let tmp = TempDir::new(ambient_authority())?;
+    /// # let dir = Arc::new(tmp.open_dir(".")?);
+    /// let mut db = Mudb::<Note>::open(dir, "notes.ndjson")?;
+    ///
+    /// let key = db.insert(None, Note("first".to_string()))?;
+    /// let key = db.insert(Some(key), Note("second".to_string()))?;
+    ///
+    /// let doc = db.get(&key.id()).unwrap();
+    /// assert_eq!(doc.key(), &key);
+    /// assert_eq!(doc.obj(), Some(&Note("second".to_string())));
+    /// assert!(doc.flags().is_empty());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn key(&self) -> &VersionedKey {
+        &self.key
+    }
+
+    pub fn obj(&self) -> Option<&T> {
+        self.obj.as_ref()
+    }
+
+    pub fn flags(&self) -> &HashSet<Flag> {
+        &self.flags
+    }
+
+    /// The raw payload of a doc written with `insert_binary`; such docs are
+    /// flagged `Flag::Binary` and have no `obj`.
+    pub fn blob(&self) -> Option<&[u8]> {
+        self.blob.as_deref()
+    }
+
+    /// When the first version of this id was written, in milliseconds since
+    /// the Unix epoch. `None` for docs written before timestamps existed.
+    pub fn created_at(&self) -> Option<u64> {
+        self.created_at
+    }
+
+    /// When this version was written, in milliseconds since the Unix epoch.
+    pub fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    // Stamps a new version, carrying `created_at` over from earlier ones.
+    fn touch(&mut self) {
+        let now = now_millis();
+
+        self.created_at.get_or_insert(now);
+        self.updated_at = Some(now);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
 }
 
 pub trait Query<'a, T>: fmt::Debug {
     fn matches(&self, obj: &'a T) -> bool;
+
+    /// A view name and key under which every match is indexed, if there is
+    /// one. `find` uses it to only test the docs that view lists, when the
+    /// view is current.
+    fn index_hint(&self) -> Option<(KString, IndexKey)> {
+        None
+    }
 }
 
 type QueryRef<'a, T> = &'a dyn Query<'a, T>;
 
+/// Adapts a plain `Fn(&T) -> bool` into a `Query`.
+pub struct FnQuery<F>(pub F);
+
+impl <F> fmt::Debug for FnQuery<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FnQuery(..)")
+    }
+}
+
+impl <'a, T, F: Fn(&T) -> bool> Query<'a, T> for FnQuery<F> {
+    fn matches(&self, obj: &T) -> bool {
+        (self.0)(obj)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum QueryOp<'a, T> {
     Id(QueryRef<'a, T>),
@@ -126,6 +412,14 @@ impl <'a, T: fmt::Debug> Query<'a, T> for QueryOp<'a, T> {
             QueryOp::Or(lhs, rhs) => lhs.matches(obj) || rhs.matches(obj),
         }
     }
+
+    fn index_hint(&self) -> Option<(KString, IndexKey)> {
+        match self {
+            QueryOp::Id(filt) => filt.index_hint(),
+            QueryOp::And(lhs, rhs) => lhs.index_hint().or_else(|| rhs.index_hint()),
+            QueryOp::Not(_) | QueryOp::Or(..) => None,
+        }
+    }
 }
 
 impl <'a, T> From<QueryRef<'a, T>> for QueryOp<'a, T> {
@@ -158,11 +452,178 @@ impl <'a, T> Not for QueryRef<'a, T> {
     }
 }
 
+/// An owned counterpart to `QueryOp`, for queries assembled at runtime.
+///
+/// Each node owns its operands, so a query can be built up from a `Vec` of
+/// filters and passed around freely; the reference-based operators on
+/// `QueryRef` remain the allocation-free option when the operands are
+/// already in scope.
+#[derive(Debug)]
+pub enum BoxedQuery<T> {
+    Id(Box<dyn for<'a> Query<'a, T> + Send + Sync>),
+    Not(Box<BoxedQuery<T>>),
+    And(Box<BoxedQuery<T>>, Box<BoxedQuery<T>>),
+    Or(Box<BoxedQuery<T>>, Box<BoxedQuery<T>>),
+}
+
+impl <T: fmt::Debug> BoxedQuery<T> {
+    pub fn new(filter: impl for<'a> Query<'a, T> + Send + Sync + 'static) -> Self {
+        BoxedQuery::Id(Box::new(filter))
+    }
+
+    /// Combines `filters` so that all of them must match, or `None` if
+    /// there are none.
+    pub fn all(filters: impl IntoIterator<Item = BoxedQuery<T>>) -> Option<Self> {
+        filters.into_iter().reduce(|lhs, rhs| lhs & rhs)
+    }
+
+    /// Combines `filters` so that any one of them must match, or `None` if
+    /// there are none.
+    pub fn any(filters: impl IntoIterator<Item = BoxedQuery<T>>) -> Option<Self> {
+        filters.into_iter().reduce(|lhs, rhs| lhs | rhs)
+    }
+}
+
+impl <'a, T: fmt::Debug> Query<'a, T> for BoxedQuery<T> {
+    fn matches(&self, obj: &'a T) -> bool {
+        match self {
+            BoxedQuery::Id(filt) => filt.matches(obj),
+            BoxedQuery::Not(filt) => !filt.matches(obj),
+            BoxedQuery::And(lhs, rhs) => lhs.matches(obj) && rhs.matches(obj),
+            BoxedQuery::Or(lhs, rhs) => lhs.matches(obj) || rhs.matches(obj),
+        }
+    }
+
+    fn index_hint(&self) -> Option<(KString, IndexKey)> {
+        match self {
+            BoxedQuery::Id(filt) => filt.index_hint(),
+            BoxedQuery::And(lhs, rhs) => lhs.index_hint().or_else(|| rhs.index_hint()),
+            BoxedQuery::Not(_) | BoxedQuery::Or(..) => None,
+        }
+    }
+}
+
+impl <T> BitAnd for BoxedQuery<T> {
+    type Output = BoxedQuery<T>;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        BoxedQuery::And(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl <T> BitOr for BoxedQuery<T> {
+    type Output = BoxedQuery<T>;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        BoxedQuery::Or(Box::new(self), Box::new(rhs))
+    }
+}
+
+impl <T> Not for BoxedQuery<T> {
+    type Output = BoxedQuery<T>;
+
+    fn not(self) -> Self::Output {
+        BoxedQuery::Not(Box::new(self))
+    }
+}
+
+// The newest stored version of `id`, found by scanning only that id's range.
+fn latest_version<'d, T: Clone + fmt::Debug + Eq>(
+    data: &'d OrdMap<VersionedKey, Doc<T>>,
+    id: &IndexKey
+) -> Option<&'d Doc<T>> {
+    data.range(id_versions(id))
+        .next_back()
+        .map(|(_k, v)| v)
+}
+
+// Stores `doc` in `data`, replacing any older version of its id, unless a
+// version at least as new is already there. Returns whether it was stored.
+fn store_newer<T: Clone + fmt::Debug + Eq>(
+    data: &mut OrdMap<VersionedKey, Doc<T>>,
+    doc: &Doc<T>
+) -> bool {
+    match latest_version(data, &doc.key.id) {
+        Some(latest) if latest.key.ver >= doc.key.ver => return false,
+        Some(latest) => {
+            let stale = latest.key.clone();
+            data.remove(&stale);
+        },
+        None => {},
+    }
+
+    data.insert(doc.key.clone(), doc.clone());
+    true
+}
+
+// Like `newest_versions`, for docs in reverse key order, where each id's
+// newest version comes first.
+fn newest_versions_rev<'d, T: Clone + fmt::Debug + Eq + 'd>(
+    docs: impl Iterator<Item = &'d Doc<T>>
+) -> impl Iterator<Item = &'d Doc<T>> {
+    let mut prev: Option<&IndexKey> = None;
+
+    docs.filter(move |doc| {
+        let newest = prev != Some(&doc.key.id);
+        prev = Some(&doc.key.id);
+        newest
+    })
+}
+
+// Narrows docs in key order down to the newest version of each id.
+fn newest_versions<'d, T: Clone + fmt::Debug + Eq + 'd>(
+    docs: impl Iterator<Item = &'d Doc<T>>
+) -> impl Iterator<Item = &'d Doc<T>> {
+    let mut docs = docs.peekable();
+
+    std::iter::from_fn(move || {
+        while let Some(doc) = docs.next() {
+            match docs.peek() {
+                Some(next) if next.key.id == doc.key.id => continue,
+                _ => return Some(doc),
+            }
+        }
+
+        None
+    })
+}
+
+// Every possible version of `id`, as a range over `VersionedKey`s.
+fn id_versions(id: &IndexKey) -> RangeInclusive<VersionedKey> {
+    VersionedKey::new(id.clone())..=VersionedKey {
+        id: id.clone(),
+        ver: u64::MAX,
+    }
+}
+
+// Identifies the exact set of versions a view was built from, so a saved
+// view can be checked against the data it's loaded next to.
+fn fingerprint<T: Clone + fmt::Debug + Eq>(data: &OrdMap<VersionedKey, Doc<T>>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    data.len().hash(&mut hasher);
+    for key in data.keys() {
+        key.hash(&mut hasher);
+    }
+
+    hasher.finish()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedView {
+    fingerprint: u64,
+    entries: Vec<(IndexKey, Vec<IndexKey>)>,
+}
+
 #[derive(Debug)]
 struct View<T: Clone + fmt::Debug + Eq> {
     snapshot: Option<OrdMap<VersionedKey, Doc<T>>>,
     inner: BTreeMap<IndexKey, HashSet<IndexKey>>,
+    // The version each id was last indexed at, and the keys it produced, so
+    // a doc can be unindexed without re-running the indexer on its old value.
+    indexed: HashMap<IndexKey, (u64, Vec<IndexKey>)>,
     indexer: Box<dyn Indexer<T>>,
+    unique: bool,
 }
 
 impl <T: Clone + fmt::Debug + Eq> View<T> {
@@ -170,52 +631,55 @@ impl <T: Clone + fmt::Debug + Eq> View<T> {
         Self {
             snapshot: None,
             inner: BTreeMap::new(),
+            indexed: HashMap::new(),
             indexer,
+            unique: false,
         }
     }
 
     #[instrument]
     pub fn build(&mut self, data: &OrdMap<VersionedKey, Doc<T>>) -> Result<()> {
         let snapshot = self.snapshot
-            .as_ref()
-            .map(|s| s.clone())
-            .unwrap_or(OrdMap::new());
+            .clone()
+            .unwrap_or_default();
 
         for delta in snapshot.diff(data) {
             self.apply_change(delta);
         }
 
-        self.snapshot = Some(snapshot.clone().clone());
+        self.snapshot = Some(data.clone());
         Ok(())
     }
 
+    // Builds from scratch, for when `data` hasn't moved forward from the
+    // snapshot and diffing against it wouldn't be meaningful.
+    fn rebuild(&mut self, data: &OrdMap<VersionedKey, Doc<T>>) -> Result<()> {
+        self.inner = BTreeMap::new();
+        self.indexed = HashMap::new();
+        self.snapshot = None;
+
+        self.build(data)
+    }
+
     #[instrument]
     fn apply_change(&mut self, delta: DiffItem<VersionedKey, Doc<T>>) {
-        let inner = &mut self.inner;
-
         match delta {
             DiffItem::Add(key, doc) => {
-                if let Some(obj) = &doc.obj {
-                    let keys = self.indexer.index(&obj);
+                let stale = self.indexed
+                    .get(&key.id)
+                    .is_some_and(|(ver, _keys)| *ver > key.ver);
 
-                    for vkey in keys {
-                        let values = inner
-                            .entry(vkey.clone())
-                            .or_insert(HashSet::new());
-
-                        values.insert(key.id());
-                    }
+                if !stale {
+                    self.reindex(key, doc.obj.as_ref());
                 }
             },
-            DiffItem::Remove(key, doc) => {
-                if let Some(obj) = &doc.obj {
-                    let keys = self.indexer.index(&obj);
-
-                    for vkey in keys {
-                        if let Some(values) = inner.get_mut(&vkey) {
-                            values.remove(&key.id());
-                        }
-                    }
+            DiffItem::Remove(key, _doc) => {
+                let current = self.indexed
+                    .get(&key.id)
+                    .is_some_and(|(ver, _keys)| *ver == key.ver);
+
+                if current {
+                    self.unindex(&key.id);
                 }
             },
             // Note: diffs generated over mudb datasets will never actually 
@@ -227,6 +691,66 @@ impl <T: Clone + fmt::Debug + Eq> View<T> {
         }
     }
 
+    // Indexes `key.id` under the keys for `obj` (none, for a tombstone),
+    // replacing whatever an earlier version was indexed under.
+    fn reindex(&mut self, key: &VersionedKey, obj: Option<&T>) {
+        self.unindex(&key.id);
+
+        let mut keys = obj
+            .map(|obj| self.indexer.index(obj))
+            .unwrap_or_default();
+        keys.sort();
+        keys.dedup();
+
+        for vkey in &keys {
+            self.inner
+                .entry(vkey.clone())
+                .or_default()
+                .insert(key.id());
+        }
+
+        self.indexed.insert(key.id(), (key.ver, keys));
+    }
+
+    // Loads a saved index that's known to match `data`.
+    fn restore(
+        &mut self,
+        entries: Vec<(IndexKey, Vec<IndexKey>)>,
+        data: &OrdMap<VersionedKey, Doc<T>>
+    ) {
+        self.inner = BTreeMap::new();
+        self.indexed = HashMap::new();
+
+        for (vkey, ids) in entries {
+            for id in ids {
+                let ver = latest_version(data, &id).map_or(0, |doc| doc.key.ver);
+
+                self.indexed
+                    .entry(id.clone())
+                    .or_insert_with(|| (ver, vec![]))
+                    .1
+                    .push(vkey.clone());
+
+                self.inner
+                    .entry(vkey.clone())
+                    .or_default()
+                    .insert(id);
+            }
+        }
+
+        self.snapshot = Some(data.clone());
+    }
+
+    fn unindex(&mut self, id: &IndexKey) {
+        if let Some((_ver, keys)) = self.indexed.remove(id) {
+            for vkey in keys {
+                if let Some(values) = self.inner.get_mut(&vkey) {
+                    values.remove(id);
+                }
+            }
+        }
+    }
+
     #[instrument]
     pub fn query(&self, lookup_key: &IndexKey) -> Vec<IndexKey> {
         self.inner
@@ -234,616 +758,5863 @@ impl <T: Clone + fmt::Debug + Eq> View<T> {
             .iter()
             .flat_map(|oids| {
                 oids.iter()
-                    .map(|id| id.clone())
+                    .cloned()
                     .collect::<Vec<IndexKey>>()
             })
             .collect()
     }
+
+    // Ids indexed under any key in `range`, each listed once.
+    pub fn query_range<R: RangeBounds<IndexKey>>(&self, range: R) -> Vec<IndexKey> {
+        let mut seen = HashSet::new();
+
+        self.inner
+            .range(range)
+            .flat_map(|(_key, oids)| oids.iter())
+            .filter(|oid| seen.insert(*oid))
+            .cloned()
+            .collect()
+    }
 }
 
-pub trait Indexer<T: Clone + fmt::Debug>: fmt::Debug {
+/// Maps a doc to the keys a view files it under.
+///
+/// A doc can have any number of keys: return one per tag to index a
+/// multi-valued field, or none to leave the doc out of the view. Repeated
+/// keys are collapsed, so a doc is listed at most once under each key.
+pub trait Indexer<T: Clone + fmt::Debug>: fmt::Debug + Send + Sync {
     fn index(&self, obj: &T) -> Vec<IndexKey>;
 }
 
-pub trait DocType: Serialize + DeserializeOwned + Clone + Eq + fmt::Debug {}
+/// Picks the doc to keep when `apply_changes` or `merge_from` brings in a
+/// version of an id that's already stored.
+///
+/// Returning `local` leaves the id alone. Anything else is stored under the
+/// incoming version, or one past the local version if that's higher, so a
+/// merged doc always supersedes the local one.
+pub trait ConflictResolver<T: Clone + fmt::Debug + Eq>: fmt::Debug + Send + Sync {
+    fn resolve(&self, local: &Doc<T>, incoming: &Doc<T>) -> Doc<T>;
+}
 
-pub struct Mudb<T: DocType> {
-    data_dir: Rc<Dir>,
+/// The default resolver: whichever side has the higher version.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LastVersionWins;
+
+impl <T: Clone + fmt::Debug + Eq> ConflictResolver<T> for LastVersionWins {
+    fn resolve(&self, local: &Doc<T>, incoming: &Doc<T>) -> Doc<T> {
+        if incoming.key.ver > local.key.ver {
+            incoming.clone()
+        } else {
+            local.clone()
+        }
+    }
+}
+
+pub trait DocType:
+    Serialize + DeserializeOwned + Clone + Eq + fmt::Debug + Send + Sync {}
+
+/// Lets arbitrary JSON be stored without a concrete doc type.
+impl DocType for serde_json::Value {}
+
+pub type JsonMudb = Mudb<serde_json::Value>;
+
+#[derive(Debug)]
+pub struct MudbOptions<T: DocType> {
+    pub codec: Box<dyn Codec<T>>,
+    pub compression: Compression,
+    pub format: Format,
+    pub on_corrupt: CorruptPolicy,
+    /// After skipping corrupt records, compact so the file only holds the
+    /// records that loaded. The skipped ones are gone for good.
+    pub repair_on_open: bool,
+    pub read_only: bool,
+    /// Keep views current on every insert, update and delete, rather than
+    /// only when `build_views` is called.
+    pub live_views: bool,
+    /// How long a doc lives after its last write before `sweep_expired`
+    /// deletes it.
+    pub ttl: Option<Duration>,
+    /// Commit automatically whenever this many changes are queued.
+    pub autocommit_threshold: Option<usize>,
+    /// Write every change to the file as it's made rather than queueing it
+    /// for `commit`, so nothing waits in memory; reads are still served from
+    /// memory. Each write then pays for a whole commit (and an fsync under
+    /// `Durability::Fsync`), and `rollback` has nothing left to undo.
+    /// Overrides `autocommit_threshold`.
+    pub write_through: bool,
+    /// Compact, dropping tombstones, after any commit that leaves more than
+    /// this fraction of the file's records dead: deleted, or superseded by
+    /// a newer version.
+    pub auto_compact_ratio: Option<f64>,
+    pub durability: Durability,
+    /// Load only the newest version of each id, rather than every version
+    /// still in the file. Saves memory, at the cost of `history`.
+    pub keep_latest_only: bool,
+    /// Keep the last object on each tombstone, so `undelete` can bring it
+    /// back. Tombstones then take as much space as the docs they replace.
+    pub retain_deleted: bool,
+    pub id_generator: Box<dyn IdGenerator>,
+    pub conflict_resolver: Box<dyn ConflictResolver<T>>,
+    /// Capacity in bytes of the buffer commits are written through.
+    pub write_buffer_capacity: usize,
+}
+
+impl <T: DocType> Default for MudbOptions<T> {
+    fn default() -> Self {
+        Self {
+            codec: Box::new(JsonLines),
+            compression: Compression::None,
+            format: Format::Ndjson,
+            on_corrupt: CorruptPolicy::Abort,
+            repair_on_open: false,
+            read_only: false,
+            live_views: false,
+            ttl: None,
+            autocommit_threshold: None,
+            write_through: false,
+            auto_compact_ratio: None,
+            durability: Durability::Flush,
+            keep_latest_only: false,
+            retain_deleted: false,
+            id_generator: Box::new(Ulid),
+            conflict_resolver: Box::new(LastVersionWins),
+            write_buffer_capacity: 8 * 1024,
+        }
+    }
+}
+
+/// What `open` does with records that fail to deserialize.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CorruptPolicy {
+    /// Fail the open with the first error.
+    #[default]
+    Abort,
+    /// Log and skip bad records, leaving them in the file to be recovered
+    /// by hand; see `MudbOptions::repair_on_open` for dropping them. A
+    /// partial trailing line left by a crash mid-commit is ended, so later
+    /// appends don't run into it. Codecs that can't read past a bad record,
+    /// like `Bincode`, always get the repair, since anything appended after
+    /// one would be lost.
+    SkipAndLog,
+}
+
+/// What `compact_with` does with deleted docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompactMode {
+    /// Write tombstones back out, so replicas and readers of the file can
+    /// still see that a doc was deleted.
+    #[default]
+    KeepTombstones,
+    /// Drop deleted docs from memory and the file entirely.
+    DropTombstones,
+}
+
+/// How far `commit` and `compact` go to make writes survive a crash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Leave commits in the write buffer until `flush`, a compaction, or
+    /// the db being dropped hands them to the OS.
+    None,
+    /// Hand writes to the OS before returning. They survive the process
+    /// crashing, but not the machine losing power.
+    #[default]
+    Flush,
+    /// Also fsync the data file on commit, and the new file and its
+    /// directory on compact, so acknowledged writes survive power loss.
+    Fsync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CommitStats {
+    /// Docs written by the commit.
+    pub docs: usize,
+    /// Bytes appended to the data file (0 for in-memory dbs). A
+    /// `Format::JsonArray` file is rewritten whole instead, and this is how
+    /// much it grew by, or 0 if the rewrite shrank it.
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompactStats {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Stored docs discarded rather than written back: superseded versions,
+    /// and tombstones under `CompactMode::DropTombstones`.
+    pub docs_dropped: usize,
+}
+
+/// Counts of what a db holds, from `summary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Summary {
+    /// Ids whose newest version is live, as `len` counts them.
+    pub live: usize,
+    /// Ids whose newest version is a tombstone.
+    pub deleted: usize,
+    /// Ids whose newest version is a blob from `insert_binary`.
+    pub binary: usize,
+    /// Older versions still held alongside a newer one, which `compact`
+    /// would drop.
+    pub stale_versions: usize,
+    pub distinct_ids: usize,
+}
+
+/// A committed mutation, as delivered to `subscribe` receivers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeEvent {
+    Inserted(VersionedKey),
+    Updated(VersionedKey),
+    Deleted(VersionedKey),
+}
+
+// The file a db is persisted to; absent for in-memory dbs.
+struct Backing {
+    data_dir: Arc<Dir>,
     filename: String,
-    write_fh: File,
+    writer: BufWriter<File>,
+    // How far into the file has been read (or written) by this handle.
+    read_offset: u64,
+    // How many records the file holds, live or not.
+    records: usize,
+    // Held for as long as the db is writable; `None` when read-only.
+    _lock: Option<LockFile>,
+}
+
+impl Backing {
+    // The end of the file, counting writes still in the buffer.
+    fn position(&self) -> io::Result<u64> {
+        let len = self.writer.get_ref().metadata()?.len();
+        Ok(len + self.writer.buffer().len() as u64)
+    }
+}
+
+pub struct Mudb<T: DocType> {
+    backing: Option<Backing>,
     data: OrdMap<VersionedKey, Doc<T>>,
+    // `data` as of the last commit, for `rollback`.
+    committed: OrdMap<VersionedKey, Doc<T>>,
     changed: Vec<Doc<T>>,
-    views: BTreeMap<KString, RefCell<View<T>>>,
+    views: BTreeMap<KString, View<T>>,
     modified: bool,
+    codec: Arc<dyn Codec<T>>,
+    compression: Compression,
+    format: Format,
+    skipped: usize,
+    read_only: bool,
+    live_views: bool,
+    ttl: Option<Duration>,
+    autocommit_threshold: Option<usize>,
+    auto_compact_ratio: Option<f64>,
+    durability: Durability,
+    validator: Option<Box<Validator<T>>>,
+    id_generator: Box<dyn IdGenerator>,
+    subscribers: Vec<Sender<ChangeEvent>>,
+    conflict_resolver: Box<dyn ConflictResolver<T>>,
+    retain_deleted: bool,
 }
 
+type Validator<T> = dyn Fn(&T) -> Result<()> + Send + Sync;
+
+type MaybeUpdate<T> = dyn FnOnce(&T) -> Option<T>;
+
 impl <T: DocType> Mudb<T> {
     #[instrument]
-    pub fn open(data_dir: Rc<Dir>, filename: &str) -> Result<Self> {
-        let mut file = data_dir.open_with(
-            filename, &default_open_options()
-        )?;
+    pub fn open(data_dir: Arc<Dir>, filename: &str) -> Result<Self> {
+        Self::open_with_options(data_dir, filename, MudbOptions::default())
+    }
+
+    pub fn builder(data_dir: Arc<Dir>, filename: &str) -> MudbBuilder<T> {
+        MudbBuilder::new(data_dir, filename)
+    }
+
+    /// Opens an existing file without write access: mutating calls return
+    /// `MudbError::ReadOnly`, and nothing is written back on drop.
+    #[instrument]
+    pub fn open_read_only(data_dir: Arc<Dir>, filename: &str) -> Result<Self> {
+        let options = MudbOptions {
+            read_only: true,
+            ..MudbOptions::default()
+        };
+
+        Self::open_with_options(data_dir, filename, options)
+    }
+
+    #[instrument]
+    pub fn open_with_codec(
+        data_dir: Arc<Dir>,
+        filename: &str,
+        codec: Box<dyn Codec<T>>
+    ) -> Result<Self> {
+        let options = MudbOptions {
+            codec,
+            ..MudbOptions::default()
+        };
+
+        Self::open_with_options(data_dir, filename, options)
+    }
+
+    #[instrument]
+    pub fn open_with_options(
+        data_dir: Arc<Dir>,
+        filename: &str,
+        options: MudbOptions<T>
+    ) -> Result<Self> {
+        let MudbOptions {
+            codec,
+            compression,
+            format,
+            on_corrupt,
+            repair_on_open,
+            read_only,
+            live_views,
+            ttl,
+            autocommit_threshold,
+            write_through,
+            auto_compact_ratio,
+            durability,
+            keep_latest_only,
+            retain_deleted,
+            mut id_generator,
+            conflict_resolver,
+            write_buffer_capacity,
+        } = options;
+
+        let (mut file, lock) = if read_only {
+            (data_dir.open(filename)?, None)
+        } else {
+            // Relative paths like "shard/01/data.ndjson" get their parent
+            // directories created; `Dir` refuses anything outside itself.
+            if let Some(parent) = Path::new(filename).parent() {
+                if !parent.as_os_str().is_empty() {
+                    data_dir.create_dir_all(parent)?;
+                }
+            }
+
+            let lock = LockFile::acquire(data_dir.clone(), filename)?;
+            (data_dir.open_with(filename, &default_open_options())?, Some(lock))
+        };
 
         let mut data = OrdMap::new();
+        let mut skipped = 0;
+        let mut records = 0;
 
         let metadata = file.metadata()?;
 
         if metadata.len() > 0 {
             let _ = file.seek(SeekFrom::Start(0))?;
-            let reader = BufReader::new(&file);
-            let desr = serde_json::Deserializer::from_reader(reader);
-            for doc in desr.into_iter() {
-                let doc: Doc<T> = doc?;
+            let reader = compression.decoder(BufReader::new(&file))?;
+            for doc in format.decode(&*codec, reader) {
+                let doc = match doc {
+                    Ok(doc) => doc,
+                    Err(MudbError::Deserialize { line, raw, source })
+                        if on_corrupt == CorruptPolicy::SkipAndLog && format == Format::Ndjson =>
+                    {
+                        warn!(filename, line, ?raw, %source, "skipping corrupt record");
+                        skipped += 1;
+                        continue;
+                    },
+                    Err(err) => return Err(err),
+                };
+                records += 1;
+
+                if keep_latest_only {
+                    match latest_version(&data, &doc.key.id) {
+                        Some(latest) if latest.key.ver >= doc.key.ver => continue,
+                        Some(latest) => {
+                            let stale = latest.key.clone();
+                            data.remove(&stale);
+                        },
+                        None => {},
+                    }
+                }
+
                 let key = doc.key.clone();
                 data.insert(key, doc);
             }
         };
 
-        Ok(Self {
+        for key in data.keys() {
+            id_generator.observe(&key.id);
+        }
+
+        let mut len = metadata.len();
+
+        // Past a bad record, a codec that can't resync would never read back
+        // what's appended, so the file is repaired for it regardless.
+        let repair = skipped > 0 && !read_only && (repair_on_open || !codec.resyncs());
+
+        if skipped > 0 && !read_only && !repair && compression == Compression::None {
+            let mut last = [0; 1];
+            let _ = file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+
+            if last != *b"\n" {
+                file.write_all(b"\n")?;
+                len += 1;
+            }
+        }
+
+        let backing = Backing {
             data_dir,
             filename: filename.to_string(),
-            write_fh: file,
+            writer: BufWriter::with_capacity(write_buffer_capacity, file),
+            read_offset: len,
+            records,
+            _lock: lock,
+        };
+
+        let mut db = Self {
+            backing: Some(backing),
+            committed: data.clone(),
             data,
             views: BTreeMap::new(),
             changed: vec![],
             modified: false,
-        })
-    }
+            codec: codec.into(),
+            compression,
+            format,
+            skipped,
+            read_only,
+            live_views,
+            ttl,
+            // Writing through is committing each change as it's queued.
+            autocommit_threshold: if write_through { Some(1) } else { autocommit_threshold },
+            auto_compact_ratio,
+            durability,
+            validator: None,
+            id_generator,
+            subscribers: vec![],
+            conflict_resolver,
+            retain_deleted,
+        };
 
-    #[instrument]
-    pub fn insert(&mut self, key: Option<VersionedKey>, obj: T) -> Result<VersionedKey> {
-        let data = &mut self.data;
+        if repair {
+            db.modified = true;
+            db.compact()?;
+        }
 
-        let key = key.unwrap_or_else(|| VersionedKey {
-            id: IndexKey::Str(KString::from(generate_ulid_string())),
-            ver: 0,
-        });
+        Ok(db)
+    }
 
-        let mut doc = data
-            .remove(&key)
-            .map(|doc| doc.clone())
-            .unwrap_or(Doc::new(key.clone(), None));
+    pub fn in_memory() -> Self {
+        let MudbOptions { codec, compression, format, .. } = MudbOptions::default();
 
-        if key.ver < doc.key.ver {
-            return Err(anyhow::anyhow!("version key provided older than last stored"));
+        Self {
+            backing: None,
+            data: OrdMap::new(),
+            committed: OrdMap::new(),
+            views: BTreeMap::new(),
+            changed: vec![],
+            modified: false,
+            codec: codec.into(),
+            compression,
+            format,
+            skipped: 0,
+            read_only: false,
+            live_views: false,
+            ttl: None,
+            autocommit_threshold: None,
+            auto_compact_ratio: None,
+            durability: Durability::Flush,
+            validator: None,
+            id_generator: Box::new(Ulid),
+            subscribers: vec![],
+            conflict_resolver: Box::new(LastVersionWins),
+            retain_deleted: false,
         }
+    }
 
-        let new_key = doc.key.incr();
-        doc.key = new_key.clone();
-        doc.obj = Some(obj);
-        data.insert(new_key.clone(), doc.clone());
+    /// Runs `validator` on every object before it's stored (by `insert`,
+    /// `update` and friends); an error aborts that write, leaving the db
+    /// untouched. `MudbError::Invalid` is there for validators to use.
+    pub fn set_validator(&mut self, validator: Box<Validator<T>>) {
+        self.validator = Some(validator);
+    }
 
-        self.modified = true;
+    /// Stores `obj` as a new version, under `key`'s id or a fresh one, and
+    /// returns its key. Writing to a deleted id revives it; use
+    /// `insert_reviving` to find out when that happens.
+    #[instrument]
+    pub fn insert(&mut self, key: Option<VersionedKey>, obj: T) -> Result<VersionedKey> {
+        self.writable()?;
 
-        self.changed.push(doc.clone());
+        let doc = self.put(key, obj)?;
+        let new_key = doc.key.clone();
+
+        self.queue(doc)?;
 
         Ok(new_key)
     }
 
+    /// Like `insert`, but also returns whether `key`'s id was deleted and
+    /// this write brought it back.
     #[instrument]
-    pub fn commit(&mut self) -> Result<usize> {
-        let queued = &self.changed.len();
+    pub fn insert_reviving(
+        &mut self,
+        key: Option<VersionedKey>,
+        obj: T
+    ) -> Result<(VersionedKey, bool)> {
+        let deleted = key.as_ref()
+            .and_then(|key| self.latest_doc(&key.id))
+            .is_some_and(|doc| doc.has_flag(&Flag::Deleted));
+
+        let new_key = self.insert(key, obj)?;
+
+        Ok((new_key, deleted))
+    }
+
+    /// Writes `obj` as the newest version of `id`, returning the new key and
+    /// whether a live version was replaced (`false` means it was created).
+    #[instrument]
+    pub fn upsert(&mut self, id: IndexKey, obj: T) -> Result<(VersionedKey, bool)> {
+        let existed = self.get(&id).is_some_and(|doc| doc.obj.is_some());
+
+        let new_key = self.insert(Some(VersionedKey::new(id)), obj)?;
+
+        Ok((new_key, existed))
+    }
+
+    /// Stores `bytes` as the newest version of `id` in place of a typed
+    /// object. The doc is flagged `Flag::Binary`, and is skipped by
+    /// `iter`, `find` and views, which only see typed objects.
+    #[instrument(skip(bytes))]
+    pub fn insert_binary(&mut self, id: IndexKey, bytes: Vec<u8>) -> Result<VersionedKey> {
+        self.writable()?;
+
+        let doc = self.store(Some(VersionedKey::new(id)), None, Some(bytes))?;
+        let new_key = doc.key.clone();
+
+        self.queue(doc)?;
+
+        Ok(new_key)
+    }
+
+    #[instrument]
+    pub fn get_binary(&self, id: &IndexKey) -> Option<Vec<u8>> {
+        self.latest_doc(id)
+            .filter(|doc| doc.has_flag(&Flag::Binary) && !doc.has_flag(&Flag::Deleted))
+            .and_then(|doc| doc.blob.clone())
+    }
+
+    #[instrument(skip(items))]
+    pub fn insert_many(
+        &mut self,
+        items: Vec<(Option<VersionedKey>, T)>
+    ) -> Result<Vec<VersionedKey>> {
+        self.writable()?;
+
+        let mut keys = Vec::with_capacity(items.len());
+        self.changed.reserve(items.len());
+
+        for (key, obj) in items {
+            let doc = self.put(key, obj)?;
+            keys.push(doc.key.clone());
+
+            self.queue(doc)?;
+        }
+
+        Ok(keys)
+    }
+
+    // Queues `doc` for the next commit, committing straight away once the
+    // queue reaches the autocommit threshold.
+    fn queue(&mut self, doc: Doc<T>) -> Result<()> {
+        self.modified = true;
+
+        self.changed.push(doc);
+
+        match self.autocommit_threshold {
+            Some(threshold) if self.changed.len() >= threshold => {
+                self.commit()?;
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(MudbError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Stores `obj` as the next version of `key` in memory, leaving the
+    // `changed`/`modified` bookkeeping to the caller.
+    fn put(&mut self, key: Option<VersionedKey>, obj: T) -> Result<Doc<T>> {
+        self.store(key, Some(obj), None)
+    }
+
+    // Like `put`, for either a typed object or a binary payload.
+    fn store(
+        &mut self,
+        key: Option<VersionedKey>,
+        obj: Option<T>,
+        blob: Option<Vec<u8>>
+    ) -> Result<Doc<T>> {
+        let key = key.unwrap_or_else(|| VersionedKey {
+            id: self.id_generator.generate(),
+            ver: 0,
+        });
+        self.id_generator.observe(&key.id);
 
-        if *queued > 0 {
-            let mut write_fh = BufWriter::new(&mut self.write_fh);
+        if let Some(obj) = &obj {
+            if let Some(validator) = &self.validator {
+                validator(obj)?;
+            }
+
+            self.check_unique(&key.id, obj)?;
+        }
+
+        // A version 0 key writes on top of whatever is stored; any other
+        // version must be at least as new as the stored one.
+        let (latest_ver, created_at) = match self.get_raw(&key.id) {
+            Some(latest) if key.ver > 0 && key.ver < latest.key.ver => {
+                return Err(MudbError::VersionConflict {
+                    expected: key,
+                    found: latest.key,
+                });
+            },
+            Some(latest) => {
+                self.data.remove(&latest.key);
+                (latest.key.ver, latest.created_at)
+            },
+            None => (0, None),
+        };
+
+        // The new payload alone decides the flags, so writing to a deleted
+        // id revives it.
+        let flags = match blob {
+            Some(_) => HashSet::from([Flag::Binary]),
+            None => HashSet::new(),
+        };
+
+        let key = VersionedKey {
+            id: key.id,
+            ver: key.ver.max(latest_ver) + 1,
+        };
+        let mut doc = Doc::with_flags(key, obj, flags);
+        doc.blob = blob;
+        doc.created_at = created_at;
+        doc.touch();
+        self.data.insert(doc.key.clone(), doc.clone());
+        self.index_change(&doc);
+
+        Ok(doc)
+    }
+
+    fn index_change(&mut self, doc: &Doc<T>) {
+        for view in self.views.values_mut() {
+            if self.live_views || view.unique {
+                view.reindex(&doc.key, doc.obj.as_ref());
+                view.snapshot = Some(self.data.clone());
+            }
+        }
+    }
 
-            for doc in &self.changed {
-                write!(&mut write_fh, "{}\n", serde_json::to_string(&doc)?)?;
+    // Fails if storing `obj` under `id` would give a unique view a second
+    // id for one of its keys.
+    fn check_unique(&self, id: &IndexKey, obj: &T) -> Result<()> {
+        for (name, view) in self.views.iter().filter(|(_name, view)| view.unique) {
+            for key in view.indexer.index(obj) {
+                let taken = view.inner
+                    .get(&key)
+                    .is_some_and(|ids| ids.iter().any(|other| other != id));
+
+                if taken {
+                    return Err(MudbError::UniqueViolation {
+                        view: name.clone(),
+                        key,
+                    });
+                }
             }
+        }
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn commit(&mut self) -> Result<usize> {
+        Ok(self.commit_stats()?.docs)
+    }
+
+    /// Like `commit`, also reporting how many bytes were appended.
+    #[instrument(fields(docs, bytes))]
+    pub fn commit_stats(&mut self) -> Result<CommitStats> {
+        self.writable()?;
+
+        let mut stats = CommitStats {
+            docs: self.changed.len(),
+            bytes: 0,
+        };
+
+        if stats.docs > 0 && self.format == Format::JsonArray && self.backing.is_some() {
+            // An array can't be appended to, so the whole file is rewritten.
+            let compacted = self.compact_with(CompactMode::KeepTombstones)?;
+            stats.bytes = compacted.bytes_after.saturating_sub(compacted.bytes_before);
+        } else if stats.docs > 0 {
+            if let Some(backing) = &mut self.backing {
+                let len_before = backing.position()?;
+                let mut encoder = self.compression.encoder(&mut backing.writer)?;
+
+                for doc in &self.changed {
+                    self.codec.encode_into(doc, &mut encoder)?;
+                }
+
+                let writer = encoder.finish()?;
+
+                if self.durability != Durability::None {
+                    writer.flush()?;
+                }
+                if self.durability == Durability::Fsync {
+                    writer.get_ref().sync_all()?;
+                }
 
-            write_fh.flush()?;
+                let len_after = backing.position()?;
+                stats.bytes = len_after - len_before;
+                backing.read_offset = len_after;
+                backing.records += stats.docs;
+            }
 
+            self.notify();
             self.changed = vec![];
+            self.committed = self.data.clone();
             self.modified = false;
         }
 
-        Ok(*queued)
+        Span::current()
+            .record("docs", &stats.docs)
+            .record("bytes", &stats.bytes);
+
+        if stats.docs > 0 && self.over_dead_ratio() {
+            self.compact_with(CompactMode::DropTombstones)?;
+        }
+
+        Ok(stats)
     }
 
-    pub fn count(&self) -> usize {
-        self.data.len()
+    // Whether `auto_compact_ratio` is set and the file has outgrown it.
+    fn over_dead_ratio(&self) -> bool {
+        let (Some(ratio), Some(backing)) = (self.auto_compact_ratio, &self.backing) else {
+            return false;
+        };
+
+        // Blobs are live too, even though `len` leaves them out.
+        let live = newest_versions(self.data.values())
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .count();
+
+        let records = backing.records;
+        let dead = records.saturating_sub(live);
+
+        records > 0 && dead as f64 / records as f64 > ratio
     }
 
-    pub fn modified(&self) -> bool {
-        self.modified
+    /// Returns a channel that receives a `ChangeEvent` for every change
+    /// made from now on, as it's committed (or compacted). Dropping the
+    /// receiver unsubscribes it.
+    pub fn subscribe(&mut self) -> Receiver<ChangeEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
     }
 
-    #[instrument]
-    pub fn exact(&self, key: &VersionedKey) -> Option<Doc<T>> {
-        self.data
-            .get(key)
-            .into_iter()
-            .map(|d| d.clone())
-            .next()
+    // Sends subscribers an event for each pending change, before `changed`
+    // is cleared.
+    fn notify(&mut self) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        // Whether each id is live, and its newest version seen so far, so
+        // older versions queued behind a newer one are passed over.
+        let mut seen: HashMap<&IndexKey, (bool, u64)> = HashMap::new();
+        let mut events = vec![];
+
+        for doc in &self.changed {
+            let (was_live, ver) = *seen.entry(&doc.key.id).or_insert_with(|| {
+                latest_version(&self.committed, &doc.key.id)
+                    .map_or((false, 0), |doc| (!doc.has_flag(&Flag::Deleted), doc.key.ver))
+            });
+
+            if doc.key.ver <= ver {
+                continue;
+            }
+
+            let deleted = doc.has_flag(&Flag::Deleted);
+            seen.insert(&doc.key.id, (!deleted, doc.key.ver));
+
+            events.push(match (deleted, was_live) {
+                (true, _) => ChangeEvent::Deleted(doc.key.clone()),
+                (false, true) => ChangeEvent::Updated(doc.key.clone()),
+                (false, false) => ChangeEvent::Inserted(doc.key.clone()),
+            });
+        }
+
+        self.subscribers.retain(|tx| {
+            events.iter().all(|event| tx.send(event.clone()).is_ok())
+        });
     }
 
+    /// Hands everything committed so far to the OS and fsyncs the data
+    /// file, whatever the db's `Durability`. With `Durability::None`, this
+    /// lets a run of commits stay buffered and be made durable at once.
     #[instrument]
-    pub fn get(&self, id: &IndexKey) -> Option<Doc<T>> {
-        self.data
-            .range(VersionedKey::new(id.clone())..)
-            .filter(|(k, _v)| &k.id == id)
-            .next_back()
-            .map(|(_k, v)| v.clone())
+    pub fn flush(&mut self) -> Result<()> {
+        self.writable()?;
+
+        if let Some(backing) = &mut self.backing {
+            backing.writer.flush()?;
+            backing.writer.get_ref().sync_all()?;
+        }
+
+        Ok(())
     }
 
-    #[instrument(skip(op))]
-    pub fn update(
-        &mut self,
-        key: &VersionedKey,
-        op: Box<dyn FnOnce(&T) -> T>
-    ) -> Option<Result<VersionedKey>> {
-        let mut result: Option<Result<VersionedKey>> = None;
+    /// Writes the newest version of every live doc to `out`, encoded with
+    /// the db's codec (NDJSON by default) but uncompressed, and returns how
+    /// many were written. Uncommitted changes are included.
+    #[instrument(skip(out))]
+    pub fn export<W: Write>(&self, out: W) -> Result<usize> {
+        let mut out = BufWriter::new(out);
+        let mut exported = 0;
+
+        for doc in self.latest().filter(|doc| !doc.has_flag(&Flag::Deleted)) {
+            self.codec.encode_into(doc, &mut out)?;
+            exported += 1;
+        }
 
-        let doc = self.exact(key)
-            .unwrap_or(Doc::new(VersionedKey::new(key.id()), None));
+        out.flush()?;
 
-        if let &Some(ref obj) = &doc.obj {
-            let key = doc.key.clone();
-            let output = op(&obj);
-            let new_key = self.insert(Some(key), output);
-            result = Some(new_key);
-            self.changed.push(doc);
+        Ok(exported)
+    }
+
+    /// Writes the newest version of every live doc to `filename` in `dest`,
+    /// in the same format as the db's own file, so the copy can be opened
+    /// like any other db. The file is written to a temporary name and
+    /// renamed into place, and the live file is left alone.
+    #[instrument(skip(dest))]
+    pub fn backup(&self, dest: &Dir, filename: &str) -> Result<()> {
+        let tmpf = TempFile::new(dest)?;
+        let mut encoder = self.compression.encoder(BufWriter::new(tmpf))?;
+        let live = self.latest().filter(|doc| !doc.has_flag(&Flag::Deleted));
+        self.format.write_all(&*self.codec, live, &mut encoder)?;
+
+        let tmpf = encoder.finish()?
+            .into_inner()
+            .map_err(|err| err.into_error())?;
+
+        if self.durability == Durability::Fsync {
+            tmpf.as_file().sync_all()?;
         }
 
-        result
+        tmpf.replace(filename)?;
+
+        Ok(())
+    }
+
+    /// Writes the live docs to `filename` in `dest`, as `backup` does, and
+    /// opens the copy as a new db with this one's codec, compression and
+    /// write settings. The fork starts with no views, validator or
+    /// subscribers, and uses the default id generator and conflict resolver.
+    #[instrument(skip(dest))]
+    pub fn fork(&self, dest: &Dir, filename: &str) -> Result<Mudb<T>>
+    where
+        T: 'static
+    {
+        self.backup(dest, filename)?;
+
+        let options = MudbOptions {
+            codec: Box::new(self.codec.clone()),
+            compression: self.compression,
+            format: self.format,
+            live_views: self.live_views,
+            ttl: self.ttl,
+            autocommit_threshold: self.autocommit_threshold,
+            auto_compact_ratio: self.auto_compact_ratio,
+            durability: self.durability,
+            retain_deleted: self.retain_deleted,
+            write_buffer_capacity: self.backing.as_ref()
+                .map_or(MudbOptions::<T>::default().write_buffer_capacity, |backing| {
+                    backing.writer.capacity()
+                }),
+            ..MudbOptions::default()
+        };
+
+        Mudb::open_with_options(Arc::new(dest.try_clone()?), filename, options)
     }
 
+    /// Reads docs written by `export` (or any stream in the db's codec) and
+    /// stores each one that's newer than the stored version of its id,
+    /// keeping its version as-is. Returns how many were applied; older or
+    /// equal versions are skipped.
+    #[instrument(skip(input))]
+    pub fn import<R: Read>(&mut self, input: R) -> Result<usize> {
+        self.writable()?;
+
+        let docs: Vec<Doc<T>> = self.codec
+            .decode(Box::new(BufReader::new(input)))
+            .collect::<Result<_>>()?;
+
+        self.apply_changes(docs)
+    }
+
+    /// Every stored doc, tombstones included, whose version is above
+    /// `since`, in version order: the changeset to hand another db's
+    /// `apply_changes`. Versions count per id, so a doc first inserted after
+    /// `since` was taken still starts at version 1; pass 0 to get everything.
     #[instrument]
-    pub fn delete(&mut self, id: VersionedKey) -> Result<Option<T>> {
-        let found = self.data.remove(&id);
-
-        if let Some(mut doc) = found {
-            let obj = doc.obj;
-            doc.key = doc.key.incr();
-            doc.obj = None;
-            doc.flags.insert(Flag::Deleted);
-            self.data.insert(id.clone(), doc);
-            self.modified = true;
-            Ok(obj)
-        } else {
-            Ok(None)
+    pub fn changes_since(&self, since: u64) -> Vec<Doc<T>> {
+        let mut docs: Vec<Doc<T>> = self.data.values()
+            .filter(|doc| doc.key.ver > since)
+            .cloned()
+            .collect();
+
+        docs.sort_by_key(|doc| doc.key.ver);
+        docs
+    }
+
+    /// Stores each of `docs`, keeping its version as-is, like `import`.
+    /// Where an id is already stored, the db's `ConflictResolver` decides
+    /// what to keep; by default that's whichever version is newer. Returns
+    /// how many were applied.
+    #[instrument(skip(docs))]
+    pub fn apply_changes(&mut self, docs: Vec<Doc<T>>) -> Result<usize> {
+        self.writable()?;
+
+        let mut applied = 0;
+
+        for doc in docs {
+            let doc = match self.latest_doc(&doc.key.id) {
+                Some(local) => {
+                    let mut resolved = self.conflict_resolver.resolve(local, &doc);
+                    if &resolved == local {
+                        continue;
+                    }
+
+                    resolved.key = VersionedKey {
+                        id: doc.key.id.clone(),
+                        ver: doc.key.ver.max(local.key.ver + 1),
+                    };
+                    resolved
+                },
+                None => doc,
+            };
+
+            if let Some(obj) = &doc.obj {
+                self.check_unique(&doc.key.id, obj)?;
+            }
+
+            if !store_newer(&mut self.data, &doc) {
+                continue;
+            }
+
+            self.index_change(&doc);
+            self.queue(doc)?;
+            applied += 1;
         }
+
+        Ok(applied)
     }
 
+    /// Loads docs that another handle has appended to the file since this
+    /// one last read or wrote it, keeping any that are newer than what's
+    /// stored, and returns how many were applied. If the file has shrunk,
+    /// because it was compacted elsewhere, all of it is read again. Does
+    /// nothing for in-memory dbs.
     #[instrument]
-    pub fn compact(&mut self) -> Result<()> {
-        if self.modified {
-            let mut tmpf = TempFile::new(&mut self.data_dir)?;
+    pub fn refresh(&mut self) -> Result<usize> {
+        let Some(backing) = &mut self.backing else {
+            return Ok(0);
+        };
+
+        backing.writer.flush()?;
+
+        // A fresh handle, since a compaction elsewhere replaces the file.
+        let mut file = backing.data_dir.open(&backing.filename)?;
+        let len = file.metadata()?.len();
+
+        if len == backing.read_offset {
+            return Ok(0);
+        }
+        // An array file can only be read whole.
+        if len < backing.read_offset || self.format == Format::JsonArray {
+            backing.read_offset = 0;
+            backing.records = 0;
+        }
+
+        let _ = file.seek(SeekFrom::Start(backing.read_offset))?;
+        let reader = self.compression.decoder(BufReader::new(&file))?;
+        let docs: Vec<Doc<T>> = self.format.decode(&*self.codec, reader).collect::<Result<_>>()?;
+        backing.read_offset = len;
+        backing.records += docs.len();
 
-            for (_key, val) in self.data.iter() {
-                write!(tmpf, "{}\n", serde_json::to_string(val)?)?;
+        let mut applied = 0;
+
+        for doc in docs {
+            if store_newer(&mut self.data, &doc) {
+                // Already on disk, so it belongs to the committed state too.
+                store_newer(&mut self.committed, &doc);
+                self.index_change(&doc);
+                applied += 1;
             }
+        }
 
-            tmpf.replace(&self.filename)?;
-            let write_fh = self.data_dir.open(&self.filename)?;
+        Ok(applied)
+    }
 
-            self.write_fh = write_fh;
-            self.changed = vec![];
-            self.modified = false;
+    /// Folds `other` into this db: the newest version of each of its ids,
+    /// tombstones included, is stored here if it's newer than the local
+    /// one (or there's no local one), keeping its version. Returns how many
+    /// ids were applied.
+    #[instrument(skip(other))]
+    pub fn merge_from(&mut self, other: &Mudb<T>) -> Result<usize> {
+        self.apply_changes(other.latest().cloned().collect())
+    }
+
+    /// Discards every change made since the last commit (or compaction),
+    /// restoring the data and views to how they were then.
+    #[instrument]
+    pub fn rollback(&mut self) -> Result<()> {
+        self.data = self.committed.clone();
+        self.changed = vec![];
+        self.modified = false;
+
+        for view in self.views.values_mut() {
+            if view.snapshot.is_some() {
+                view.rebuild(&self.data)?;
+            }
         }
 
         Ok(())
     }
 
+    /// Every stored doc, counting each version and tombstone; see `len` for
+    /// the number of live docs.
+    pub fn count(&self) -> usize {
+        self.data.len()
+    }
+
+    /// How many live docs `iter` yields. Blobs from `insert_binary` aren't
+    /// counted; `summary` reports them separately.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Whether `iter` would yield nothing, though tombstones and blobs may
+    /// be stored.
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Live, deleted and stale counts, taken in a single pass over the data.
     #[instrument]
-    pub fn find<'a>(&'a self, filter: QueryRef<'a, T>) -> Vec<T> {
-        self.data.values()
-            .flat_map(|doc: &'a Doc<T>| doc.obj.as_ref())
-            .filter(|obj| filter.matches(obj))
-            .map(|obj| obj.clone())
-            .collect()
+    pub fn summary(&self) -> Summary {
+        let mut summary = Summary::default();
+        let mut docs = self.data.values().peekable();
+
+        while let Some(doc) = docs.next() {
+            match docs.peek() {
+                Some(next) if next.key.id == doc.key.id => summary.stale_versions += 1,
+                _ if doc.has_flag(&Flag::Deleted) => summary.deleted += 1,
+                _ if doc.obj.is_none() => summary.binary += 1,
+                _ => summary.live += 1,
+            }
+        }
+
+        summary.distinct_ids = summary.live + summary.deleted + summary.binary;
+        summary
+    }
+
+    pub fn modified(&self) -> bool {
+        self.modified
+    }
+
+    /// How many corrupt records `open` skipped under `CorruptPolicy::SkipAndLog`.
+    pub fn skipped(&self) -> usize {
+        self.skipped
     }
 
     #[instrument]
-    pub fn add_view(
-        &mut self,
-        name: &KString,
-        indexer: Box<dyn Indexer<T>>
-    ) -> Result<()> {
-        self.views.insert(
-            name.clone(),
-            RefCell::new(View::new(indexer))
-        );
-        Ok(())
+    pub fn exact(&self, key: &VersionedKey) -> Option<Doc<T>> {
+        self.data
+            .get(key)
+            .cloned()
+    }
+
+    /// Whether `id` has a live (not deleted) latest version.
+    pub fn contains(&self, id: &IndexKey) -> bool {
+        self.latest_doc(id)
+            .is_some_and(|doc| !doc.has_flag(&Flag::Deleted))
+    }
+
+    /// Whether exactly `key` is stored, whatever it holds.
+    pub fn contains_exact(&self, key: &VersionedKey) -> bool {
+        self.data.contains_key(key)
     }
 
+    /// Fetches version `ver` of `id`, if it's still stored (see `history`).
     #[instrument]
-    pub fn build_views(&mut self) -> Result<()> {
-        for view in self.views.values() {
-            let mut view_ref = view.borrow_mut();
-            (*view_ref).build(&self.data)?;
-        }
+    pub fn get_version(&self, id: &IndexKey, ver: u64) -> Option<Doc<T>> {
+        self.exact(&VersionedKey::at(id.clone(), ver))
+    }
 
-        Ok(())
+    /// The newest version of `id`, or `None` if there isn't one or it was
+    /// deleted.
+    #[instrument]
+    pub fn get(&self, id: &IndexKey) -> Option<Doc<T>> {
+        self.latest_doc(id)
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .cloned()
     }
 
+    /// Like `get`, but returns the tombstone of a deleted id.
     #[instrument]
-    pub fn find_by_view(&self, name: &str, lookup_key: IndexKey) -> Vec<T> {
-        if let Some(view) = self.views.get(name) {
-            let view = (*view).borrow();
-            let keys = view.query(&lookup_key);
+    pub fn get_raw(&self, id: &IndexKey) -> Option<Doc<T>> {
+        self.latest_doc(id).cloned()
+    }
 
-            keys.iter()
-                .flat_map(|key| self.get(key))
-                .flat_map(|doc| doc.obj.clone())
-                .collect()
-        } else {
-            vec![]
-        }
+    #[instrument]
+    pub fn get_many(&self, ids: &[IndexKey]) -> BTreeMap<IndexKey, Doc<T>> {
+        ids.iter()
+            .flat_map(|id| self.latest_doc(id))
+            .filter(|doc| doc.obj.is_some() && !doc.has_flag(&Flag::Deleted))
+            .map(|doc| (doc.key.id(), doc.clone()))
+            .collect()
     }
-}
 
+    /// Every stored version of `id`, oldest first.
+    ///
+    /// Only versions still held in memory are returned: those loaded from
+    /// the file plus the newest one written since. Writing a new version
+    /// replaces the previous in-memory one, and compaction keeps only the
+    /// newest version of each id.
+    #[instrument]
+    pub fn history(&self, id: &IndexKey) -> Vec<Doc<T>> {
+        self.data
+            .range(id_versions(id))
+            .map(|(_key, doc)| doc.clone())
+            .collect()
+    }
 
-impl <T: DocType> Drop for Mudb<T> {
-    fn drop(&mut self) {
-        let res = self.commit().and_then(|_| self.compact());
-        if res.is_err() {
-            error!("failed to commit db changes on drop: {:?}", res);
-        }
+    fn latest_doc(&self, id: &IndexKey) -> Option<&Doc<T>> {
+        latest_version(&self.data, id)
     }
-}
 
-impl <T: DocType> fmt::Debug for Mudb<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Mudb")
-            .field("filename", &self.filename)
-            .finish()
+    /// A consistent read-only view of the current docs, including
+    /// uncommitted writes, that later writes won't change.
+    #[instrument]
+    pub fn snapshot(&self) -> ReadSnapshot<T> {
+        ReadSnapshot::new(self.data.clone())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use anyhow::Result;
-    use cap_std::ambient_authority;
-    use cap_std::fs::Dir;
-    use cap_tempfile::TempDir;
-    use serde::{Deserialize, Serialize};
-    use std::rc::Rc;
-    use test_log::test;
+    pub fn iter(&self) -> impl Iterator<Item = (VersionedKey, &T)> {
+        self.latest()
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .flat_map(|doc| doc.obj.as_ref().map(|obj| (doc.key.clone(), obj)))
+    }
 
-    const DATA_DIR: &str = ".data";
+    // Yields only the newest stored version of each id, in id order.
+    fn latest(&self) -> impl Iterator<Item = &Doc<T>> {
+        newest_versions(self.data.values())
+    }
 
-    fn data_dir() -> Result<(TempDir, Dir)> {
-        let tmpd = TempDir::new(ambient_authority()).unwrap();
-        let _ = tmpd.create_dir(DATA_DIR)?;
-        let data = tmpd.open_dir(DATA_DIR)?;
-        Ok((tmpd, data))
+    // Like `latest`, but in reverse id order.
+    fn latest_rev(&self) -> impl Iterator<Item = &Doc<T>> {
+        newest_versions_rev(self.data.values().rev())
     }
 
-    fn msg_fixture() -> Vec<TestMessage> {
-        vec![
-            TestMessage::Of {
-                kind: 1,
-                val: "hello everyone".to_string(),
-            },
-            TestMessage::Of {
-                kind: 1,
-                val: "goodbye my friends".to_string(),
+    /// The ids whose newest version is a tombstone, in id order.
+    #[instrument]
+    pub fn deleted_ids(&self) -> Vec<IndexKey> {
+        self.latest()
+            .filter(|doc| doc.has_flag(&Flag::Deleted))
+            .map(|doc| doc.key.id())
+            .collect()
+    }
+
+    /// The largest numeric id of a live doc, if there is one.
+    #[instrument]
+    pub fn max_num_id(&self) -> Option<i64> {
+        let nums = VersionedKey::new(IndexKey::Num(i64::MIN))..=VersionedKey {
+            id: IndexKey::Num(i64::MAX),
+            ver: u64::MAX,
+        };
+
+        newest_versions_rev(self.data.range(nums).map(|(_key, doc)| doc).rev())
+            .find_map(|doc| match &doc.key.id {
+                IndexKey::Num(num) if !doc.has_flag(&Flag::Deleted) => Some(*num),
+                _ => None,
+            })
+    }
+
+    // Live docs, newest id first.
+    fn live_rev(&self) -> impl Iterator<Item = (IndexKey, &T)> {
+        self.latest_rev()
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .flat_map(|doc| doc.obj.as_ref().map(|obj| (doc.key.id(), obj)))
+    }
+
+    /// The live doc with the lowest id. ULID ids sort by creation time, so
+    /// by default this is the oldest doc.
+    #[instrument]
+    pub fn first(&self) -> Option<(IndexKey, T)> {
+        self.iter()
+            .next()
+            .map(|(key, obj)| (key.id(), obj.clone()))
+    }
+
+    /// The live doc with the highest id; by default, the newest doc.
+    #[instrument]
+    pub fn last(&self) -> Option<(IndexKey, T)> {
+        self.live_rev()
+            .next()
+            .map(|(id, obj)| (id, obj.clone()))
+    }
+
+    /// The `n` live docs with the highest ids, highest first; by default,
+    /// the most recently created.
+    #[instrument]
+    pub fn recent(&self, n: usize) -> Vec<T> {
+        self.live_rev()
+            .take(n)
+            .map(|(_id, obj)| obj.clone())
+            .collect()
+    }
+
+    /// Live docs whose id is a string starting with `prefix`, in id order.
+    #[instrument]
+    pub fn scan_prefix(&self, prefix: &str) -> Vec<(IndexKey, T)> {
+        let start = VersionedKey::new(IndexKey::Str(KString::from_ref(prefix)));
+
+        let docs = self.data
+            .range(start..)
+            .map(|(_key, doc)| doc)
+            .take_while(|doc| match &doc.key.id {
+                IndexKey::Str(id) => id.starts_with(prefix),
+                _ => false,
+            });
+
+        newest_versions(docs)
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .flat_map(|doc| doc.obj.clone().map(|obj| (doc.key.id(), obj)))
+            .collect()
+    }
+
+    #[instrument(skip(op))]
+    pub fn update(
+        &mut self,
+        key: &VersionedKey,
+        op: Box<dyn FnOnce(&T) -> T>
+    ) -> Option<Result<VersionedKey>> {
+        if let Err(err) = self.writable() {
+            return Some(Err(err));
+        }
+
+        let mut result: Option<Result<VersionedKey>> = None;
+
+        let doc = self.exact(key)
+            .unwrap_or(Doc::new(VersionedKey::new(key.id()), None));
+
+        if let Some(obj) = &doc.obj {
+            let key = doc.key.clone();
+            let output = op(obj);
+
+            result = Some(self.insert(Some(key), output));
+        }
+
+        result
+    }
+
+    /// Like `update`, but also returns the object as it was before `op`
+    /// ran, saving a `get` beforehand.
+    #[instrument(skip(op))]
+    pub fn update_returning(
+        &mut self,
+        key: &VersionedKey,
+        op: Box<dyn FnOnce(&T) -> T>
+    ) -> Option<Result<(VersionedKey, T)>> {
+        if let Err(err) = self.writable() {
+            return Some(Err(err));
+        }
+
+        let old = self.exact(key)?.obj?;
+        let output = op(&old);
+
+        Some(self.insert(Some(key.clone()), output).map(|new_key| (new_key, old)))
+    }
+
+    /// Like `update`, except `op` can return `None` to delete the doc, in
+    /// which case the tombstone's key is returned.
+    #[instrument(skip(op))]
+    pub fn update_maybe(
+        &mut self,
+        key: &VersionedKey,
+        op: Box<MaybeUpdate<T>>
+    ) -> Option<Result<VersionedKey>> {
+        if let Err(err) = self.writable() {
+            return Some(Err(err));
+        }
+
+        let obj = self.exact(key)?.obj?;
+
+        Some(match op(&obj) {
+            Some(output) => self.insert(Some(key.clone()), output),
+            None => self.tombstone(key).map(|_obj| key.incr()),
+        })
+    }
+
+    /// Applies `op` only if `expected` is still the newest version of its
+    /// id, failing with `MudbError::VersionConflict` otherwise (including
+    /// when the id is missing or deleted).
+    #[instrument(skip(op))]
+    pub fn update_cas(
+        &mut self,
+        expected: &VersionedKey,
+        op: Box<dyn FnOnce(&T) -> T>
+    ) -> Result<VersionedKey> {
+        let latest = self.latest_doc(&expected.id);
+
+        let current = latest
+            .filter(|doc| doc.key == *expected && !doc.has_flag(&Flag::Deleted))
+            .and_then(|doc| doc.obj.clone());
+
+        let Some(obj) = current else {
+            return Err(MudbError::VersionConflict {
+                expected: expected.clone(),
+                found: latest.map_or_else(|| VersionedKey::new(expected.id()), |doc| doc.key.clone()),
+            });
+        };
+
+        self.insert(Some(expected.clone()), op(&obj))
+    }
+
+    #[instrument]
+    pub fn delete(&mut self, id: VersionedKey) -> Result<Option<T>> {
+        self.writable()?;
+
+        self.tombstone(&id)
+    }
+
+    /// Deletes every live doc matching `filter` and returns how many there
+    /// were. The docs are only borrowed while the matching keys are
+    /// collected, so `filter` has to accept them for any lifetime.
+    #[instrument]
+    pub fn delete_by_query(&mut self, filter: &dyn for<'a> Query<'a, T>) -> Result<usize> {
+        self.writable()?;
+
+        let keys: Vec<VersionedKey> = self.iter()
+            .filter(|(_key, obj)| filter.matches(obj))
+            .map(|(key, _obj)| key)
+            .collect();
+
+        for key in &keys {
+            self.tombstone(key)?;
+        }
+
+        Ok(keys.len())
+    }
+
+    /// Replaces every live doc with `f` applied to it, as a new version,
+    /// and returns how many were rewritten.
+    ///
+    /// This isn't atomic. `f` runs on every doc before any is written, but
+    /// the writes then go in one at a time, so one failing (on the
+    /// validator, say) leaves the docs before it rewritten and queued, and
+    /// the rest as they were.
+    #[instrument(skip(f))]
+    pub fn map_all(&mut self, f: impl Fn(&T) -> T) -> Result<usize> {
+        self.writable()?;
+
+        let updates: Vec<(VersionedKey, T)> = self.iter()
+            .map(|(key, obj)| (key, f(obj)))
+            .collect();
+
+        let count = updates.len();
+        for (key, obj) in updates {
+            self.insert(Some(key), obj)?;
+        }
+
+        Ok(count)
+    }
+
+    /// Deletes every live doc last written more than the configured `ttl`
+    /// ago, returning how many there were. Docs without a timestamp never
+    /// expire, and nothing does if no `ttl` was set.
+    #[instrument]
+    pub fn sweep_expired(&mut self) -> Result<usize> {
+        self.writable()?;
+
+        let Some(ttl) = self.ttl else {
+            return Ok(0);
+        };
+
+        let cutoff = now_millis().saturating_sub(ttl.as_millis() as u64);
+
+        let expired: Vec<VersionedKey> = self.latest()
+            .filter(|doc| doc.obj.is_some() && !doc.has_flag(&Flag::Deleted))
+            .filter(|doc| doc.updated_at.is_some_and(|at| at < cutoff))
+            .map(|doc| doc.key.clone())
+            .collect();
+
+        for key in &expired {
+            self.tombstone(key)?;
+        }
+
+        Ok(expired.len())
+    }
+
+    // Replaces the doc stored at `key` with a deleted marker one version up,
+    // queueing the marker for the next commit. Like `store`, refuses a key
+    // older than the newest stored version of its id.
+    fn tombstone(&mut self, key: &VersionedKey) -> Result<Option<T>> {
+        match self.latest_doc(&key.id) {
+            Some(latest) if key.ver > 0 && key.ver < latest.key.ver => {
+                return Err(MudbError::VersionConflict {
+                    expected: key.clone(),
+                    found: latest.key.clone(),
+                });
             },
-            TestMessage::Empty {
-                kind: 0,
+            _ => {},
+        }
+
+        let Some(old) = self.data.remove(key) else {
+            return Ok(None);
+        };
+
+        let mut flags = old.flags;
+        flags.insert(Flag::Deleted);
+
+        let mut doc = Doc::with_flags(old.key.incr(), None, flags);
+        doc.blob = old.blob;
+        doc.created_at = old.created_at;
+        if self.retain_deleted {
+            doc.retained = old.obj.clone();
+        }
+        doc.touch();
+        self.data.insert(doc.key.clone(), doc.clone());
+        self.index_change(&doc);
+
+        self.queue(doc)?;
+
+        Ok(old.obj)
+    }
+
+    /// Drops every stored version of each id whose tombstone was written
+    /// before `cutoff`, and returns how many ids were purged. Tombstones
+    /// from before timestamps existed count as older than any cutoff. If
+    /// any are purged, the file is rewritten without them by `compact`,
+    /// which also commits whatever is pending.
+    #[instrument]
+    pub fn purge_tombstones_before(&mut self, cutoff: SystemTime) -> Result<usize> {
+        self.writable()?;
+
+        let cutoff = cutoff.duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        let purged: Vec<IndexKey> = self.latest()
+            .filter(|doc| doc.has_flag(&Flag::Deleted))
+            .filter(|doc| doc.updated_at.is_none_or(|at| at < cutoff))
+            .map(|doc| doc.key.id())
+            .collect();
+
+        for id in &purged {
+            let versions: Vec<VersionedKey> = self.data.range(id_versions(id))
+                .map(|(key, _doc)| key.clone())
+                .collect();
+
+            for key in &versions {
+                self.data.remove(key);
             }
-        ]
+        }
+
+        if !purged.is_empty() {
+            self.modified = true;
+            self.compact()?;
+        }
+
+        Ok(purged.len())
+    }
+
+    /// Restores the object a deleted id held before it was deleted, as a
+    /// new version, and returns it. Only tombstones written under
+    /// `retain_deleted` remember their object: for anything else, including
+    /// ids that aren't deleted, this returns `None`.
+    #[instrument]
+    pub fn undelete(&mut self, id: &IndexKey) -> Result<Option<T>> {
+        self.writable()?;
+
+        let Some((key, obj)) = self.latest_doc(id)
+            .filter(|doc| doc.has_flag(&Flag::Deleted))
+            .and_then(|doc| doc.retained.clone().map(|obj| (doc.key.clone(), obj)))
+        else {
+            return Ok(None);
+        };
+
+        self.insert(Some(key), obj.clone())?;
+
+        Ok(Some(obj))
+    }
+
+    /// Deletes everything, in memory and on disk, and empties every view.
+    /// The file is replaced by an empty one in a single rename (as with
+    /// `compact`), so a crash leaves either the old contents or none.
+    /// Uncommitted changes are discarded, and subscribers get a `Deleted`
+    /// event for each committed doc.
+    #[instrument]
+    pub fn clear(&mut self) -> Result<()> {
+        self.writable()?;
+
+        self.data = OrdMap::new();
+
+        // Pending changes were never committed, so subscribers only hear
+        // that each committed doc is gone.
+        self.changed = newest_versions(self.committed.values())
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .map(|doc| Doc::with_flags(doc.key.incr(), None, HashSet::from([Flag::Deleted])))
+            .collect();
+
+        self.modified = true;
+        self.compact()?;
+
+        for view in self.views.values_mut() {
+            view.rebuild(&self.data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits, syncs and compacts, as dropping the db does, but returns any
+    /// error instead of only logging it. If this fails, the drop that
+    /// follows tries once more.
+    #[instrument]
+    pub fn close(mut self) -> Result<()> {
+        self.shutdown()?;
+
+        // Let go of the file, so the drop that follows has nothing to redo.
+        self.backing = None;
+
+        Ok(())
+    }
+
+    // The final commit, flush and compaction shared by `close` and `drop`.
+    fn shutdown(&mut self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        self.commit()?;
+        self.flush()?;
+        self.compact()?;
+
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn compact(&mut self) -> Result<CompactStats> {
+        self.compact_with(CompactMode::KeepTombstones)
+    }
+
+    #[instrument(fields(bytes_before, bytes_after, docs_dropped))]
+    pub fn compact_with(&mut self, mode: CompactMode) -> Result<CompactStats> {
+        self.writable()?;
+
+        let size = self.backing
+            .as_ref()
+            .map_or(Ok(0), |backing| backing.position())?;
+
+        let mut stats = CompactStats {
+            bytes_before: size,
+            bytes_after: size,
+            docs_dropped: 0,
+        };
+
+        // Versions loaded from the file that a newer one supersedes.
+        let superseded: Vec<VersionedKey> = self.data.keys()
+            .zip(self.data.keys().skip(1))
+            .filter(|(key, next)| key.id == next.id)
+            .map(|(key, _next)| key.clone())
+            .collect();
+
+        for key in &superseded {
+            self.data.remove(key);
+        }
+
+        stats.docs_dropped = superseded.len();
+        self.modified |= !superseded.is_empty();
+
+        if mode == CompactMode::DropTombstones {
+            let deleted: HashSet<IndexKey> = self.latest()
+                .filter(|doc| doc.has_flag(&Flag::Deleted))
+                .map(|doc| doc.key.id())
+                .collect();
+
+            let doomed: Vec<VersionedKey> = self.data.keys()
+                .filter(|key| deleted.contains(&key.id))
+                .cloned()
+                .collect();
+
+            for key in &doomed {
+                self.data.remove(key);
+            }
+
+            stats.docs_dropped += doomed.len();
+            self.modified |= !doomed.is_empty();
+        }
+
+        if self.modified {
+            if let Some(backing) = &mut self.backing {
+                let tmpf = TempFile::new(&backing.data_dir)?;
+                let mut encoder = self.compression.encoder(tmpf)?;
+                self.format.write_all(&*self.codec, self.data.values(), &mut encoder)?;
+
+                let tmpf = encoder.finish()?;
+
+                if self.durability == Durability::Fsync {
+                    tmpf.as_file().sync_all()?;
+                }
+
+                tmpf.replace(&backing.filename)?;
+
+                // The rename itself only survives a crash once the directory
+                // entry is synced too.
+                if self.durability == Durability::Fsync {
+                    backing.data_dir.open(".")?.sync_all()?;
+                }
+
+                let write_fh = backing.data_dir.open_with(
+                    &backing.filename, &default_open_options()
+                )?;
+
+                stats.bytes_after = write_fh.metadata()?.len();
+                backing.writer = BufWriter::with_capacity(backing.writer.capacity(), write_fh);
+                backing.read_offset = stats.bytes_after;
+                backing.records = self.data.len();
+            }
+
+            self.notify();
+            self.changed = vec![];
+            self.committed = self.data.clone();
+            self.modified = false;
+        }
+
+        Span::current()
+            .record("bytes_before", &stats.bytes_before)
+            .record("bytes_after", &stats.bytes_after)
+            .record("docs_dropped", &stats.docs_dropped);
+
+        Ok(stats)
+    }
+
+    #[instrument(fields(scanned, matched))]
+    pub fn find<'a>(&'a self, filter: QueryRef<'a, T>) -> Vec<T> {
+        // Only trust a view that was built from exactly the current data.
+        let hinted = filter.index_hint().and_then(|(name, key)| {
+            self.views.get(&name)
+                .filter(|view| view.snapshot.as_ref().is_some_and(|snap| snap.ptr_eq(&self.data)))
+                .map(|view| view.query(&key))
+        });
+
+        let mut scanned = 0;
+
+        let found: Vec<T> = if let Some(mut ids) = hinted {
+            ids.sort();
+            ids.iter()
+                .flat_map(|id| self.latest_doc(id))
+                .filter(|doc| !doc.has_flag(&Flag::Deleted))
+                .flat_map(|doc| doc.obj.as_ref())
+                .inspect(|_| scanned += 1)
+                .filter(|obj| filter.matches(obj))
+                .cloned()
+                .collect()
+        } else {
+            self.iter()
+                .map(|(_key, obj)| obj)
+                .inspect(|_| scanned += 1)
+                .filter(|obj| filter.matches(obj))
+                .cloned()
+                .collect()
+        };
+
+        Span::current()
+            .record("scanned", &scanned)
+            .record("matched", &found.len());
+
+        found
+    }
+
+    /// Like `find`, but borrows the matching live docs instead of cloning
+    /// them.
+    #[instrument]
+    pub fn find_refs<'a>(&'a self, filter: QueryRef<'a, T>) -> Vec<&'a T> {
+        self.iter()
+            .map(|(_key, obj)| obj)
+            .filter(|obj| filter.matches(obj))
+            .collect()
+    }
+
+    #[instrument]
+    pub fn count_match<'a>(&'a self, filter: QueryRef<'a, T>) -> usize {
+        self.iter()
+            .filter(|(_key, obj)| filter.matches(obj))
+            .count()
+    }
+
+    #[instrument]
+    pub fn any_match<'a>(&'a self, filter: QueryRef<'a, T>) -> bool {
+        self.iter()
+            .any(|(_key, obj)| filter.matches(obj))
+    }
+
+    /// Up to `limit` live docs matching `filter`, in no promised order.
+    /// Scanning stops as soon as `limit` matches are found.
+    #[instrument]
+    pub fn find_limited<'a>(&'a self, filter: QueryRef<'a, T>, limit: usize) -> Vec<T> {
+        self.iter()
+            .map(|(_key, obj)| obj)
+            .filter(|obj| filter.matches(obj))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    #[instrument]
+    pub fn find_paged<'a>(
+        &'a self,
+        filter: QueryRef<'a, T>,
+        offset: usize,
+        limit: usize
+    ) -> Vec<T> {
+        self.iter()
+            .map(|(_key, obj)| obj)
+            .filter(|obj| filter.matches(obj))
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Up to `limit` live docs in id order, starting after the id of the
+    /// cursor `after` (or from the start), plus the cursor to pass in for
+    /// the next page, or `None` once there are no more.
+    #[instrument]
+    pub fn scan_from(
+        &self,
+        after: Option<VersionedKey>,
+        limit: usize
+    ) -> (Vec<(VersionedKey, T)>, Option<VersionedKey>) {
+        let start = match after {
+            Some(key) => Bound::Excluded(VersionedKey { id: key.id, ver: u64::MAX }),
+            None => Bound::Unbounded,
+        };
+
+        let mut page: Vec<(VersionedKey, T)> = newest_versions(
+            self.data.range((start, Bound::Unbounded)).map(|(_key, doc)| doc)
+        )
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .flat_map(|doc| doc.obj.clone().map(|obj| (doc.key.clone(), obj)))
+            .take(limit + 1)
+            .collect();
+
+        let cursor = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(|(key, _obj)| key.clone())
+        } else {
+            None
+        };
+
+        (page, cursor)
+    }
+
+    #[instrument]
+    pub fn add_view(
+        &mut self,
+        name: &KString,
+        indexer: Box<dyn Indexer<T>>
+    ) -> Result<()> {
+        let mut view = View::new(indexer);
+
+        if self.live_views {
+            view.build(&self.data)?;
+        }
+
+        self.views.insert(name.clone(), view);
+        Ok(())
+    }
+
+    /// Adds a view that allows at most one id per key: writes that would
+    /// index a second id under a taken key fail with
+    /// `MudbError::UniqueViolation`. Unique views are always kept current,
+    /// whether or not `live_views` is set.
+    #[instrument]
+    pub fn add_unique_view(
+        &mut self,
+        name: &KString,
+        indexer: Box<dyn Indexer<T>>
+    ) -> Result<()> {
+        let mut view = View::new(indexer);
+        view.unique = true;
+        view.build(&self.data)?;
+
+        self.views.insert(name.clone(), view);
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn remove_view(&mut self, name: &str) -> bool {
+        self.views.remove(name).is_some()
+    }
+
+    pub fn view_names(&self) -> Vec<KString> {
+        self.views.keys().cloned().collect()
+    }
+
+    #[instrument]
+    pub fn build_views(&mut self) -> Result<()> {
+        for view in self.views.values_mut() {
+            view.build(&self.data)?;
+        }
+
+        Ok(())
+    }
+
+    /// Like `build_views`, but builds each view on its own rayon worker.
+    #[cfg(feature = "rayon")]
+    #[instrument]
+    pub fn par_build_views(&mut self) -> Result<()> {
+        let data = &self.data;
+        self.views
+            .par_iter_mut()
+            .try_for_each(|(_name, view)| view.build(data))
+    }
+
+    /// Writes the index of every view to `<filename>.views`, so a later
+    /// `load_views` can skip rebuilding them. Does nothing for in-memory dbs.
+    #[instrument]
+    pub fn save_views(&self) -> Result<()> {
+        self.writable()?;
+
+        let Some(backing) = &self.backing else {
+            return Ok(());
+        };
+
+        let saved: BTreeMap<&KString, SavedView> = self.views.iter()
+            .map(|(name, view)| {
+                let fingerprint = view.snapshot
+                    .as_ref()
+                    .map(fingerprint)
+                    .unwrap_or_else(|| fingerprint(&OrdMap::<VersionedKey, Doc<T>>::new()));
+
+                let entries = view.inner.iter()
+                    .map(|(key, ids)| (key.clone(), ids.iter().cloned().collect()))
+                    .collect();
+
+                (name, SavedView { fingerprint, entries })
+            })
+            .collect();
+
+        let mut tmpf = BufWriter::new(TempFile::new(&backing.data_dir)?);
+        serde_json::to_writer(&mut tmpf, &saved)
+            .map_err(|err| MudbError::Serialize(err.into()))?;
+
+        let tmpf = tmpf.into_inner().map_err(|err| err.into_error())?;
+        tmpf.replace(views_filename(&backing.filename))?;
+
+        Ok(())
+    }
+
+    /// Restores views registered with `add_view` from `<filename>.views`.
+    /// Views that weren't saved, or were saved against different data than
+    /// is loaded now, are rebuilt instead. Returns how many were restored.
+    #[instrument]
+    pub fn load_views(&mut self) -> Result<usize> {
+        let mut saved: BTreeMap<KString, SavedView> = match &self.backing {
+            Some(backing) => match backing.data_dir.read(views_filename(&backing.filename)) {
+                Ok(raw) => serde_json::from_slice(&raw).unwrap_or_else(|err| {
+                    warn!(filename = backing.filename, %err, "ignoring unreadable saved views");
+                    BTreeMap::new()
+                }),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(err) => return Err(err.into()),
+            },
+            None => BTreeMap::new(),
+        };
+
+        let current = fingerprint(&self.data);
+        let mut restored = 0;
+
+        for (name, view) in self.views.iter_mut() {
+            match saved.remove(name) {
+                Some(saved) if saved.fingerprint == current => {
+                    view.restore(saved.entries, &self.data);
+                    restored += 1;
+                },
+                _ => {
+                    view.rebuild(&self.data)?;
+                },
+            }
+        }
+
+        Ok(restored)
+    }
+
+    #[instrument(fields(hit, ids))]
+    pub fn find_by_view(&self, name: &str, lookup_key: IndexKey) -> Vec<T> {
+        if let Some(view) = self.views.get(name) {
+            let keys = view.query(&lookup_key);
+            Span::current()
+                .record("hit", &!keys.is_empty())
+                .record("ids", &keys.len());
+
+            keys.iter()
+                .flat_map(|key| self.get(key))
+                .flat_map(|doc| doc.obj.clone())
+                .collect()
+        } else {
+            Span::current().record("hit", &false);
+            vec![]
+        }
+    }
+
+    /// Like `find_by_view`, but pairs each object with the key of the
+    /// version it came from, ready to pass to `update`.
+    #[instrument]
+    pub fn find_by_view_keyed(
+        &self,
+        name: &str,
+        lookup_key: IndexKey
+    ) -> Vec<(VersionedKey, T)> {
+        let Some(view) = self.views.get(name) else {
+            return vec![];
+        };
+
+        view.query(&lookup_key)
+            .iter()
+            .flat_map(|id| self.get(id))
+            .flat_map(|doc| doc.obj.map(|obj| (doc.key, obj)))
+            .collect()
+    }
+
+    /// How many ids each key of view `name` currently maps to. Keys with no
+    /// ids left are omitted; a missing view gives an empty map.
+    #[instrument]
+    pub fn view_counts(&self, name: &str) -> BTreeMap<IndexKey, usize> {
+        self.views
+            .get(name)
+            .map(|view| {
+                view.inner.iter()
+                    .filter(|(_key, ids)| !ids.is_empty())
+                    .map(|(key, ids)| (key.clone(), ids.len()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Folds the live docs under each key of view `name` into an
+    /// accumulator starting from `init`, in id order. Keys with no live docs
+    /// are omitted; a missing view gives an empty map.
+    #[instrument(skip(init, fold))]
+    pub fn group_by<A: Clone>(
+        &self,
+        name: &str,
+        init: A,
+        fold: impl Fn(A, &T) -> A
+    ) -> BTreeMap<IndexKey, A> {
+        let Some(view) = self.views.get(name) else {
+            return BTreeMap::new();
+        };
+
+        view.inner.iter()
+            .filter_map(|(key, ids)| {
+                let mut ids: Vec<&IndexKey> = ids.iter().collect();
+                ids.sort();
+
+                let mut objs = ids.into_iter()
+                    .flat_map(|id| self.latest_doc(id))
+                    .filter(|doc| !doc.has_flag(&Flag::Deleted))
+                    .flat_map(|doc| doc.obj.as_ref())
+                    .peekable();
+
+                objs.peek()?;
+                Some((key.clone(), objs.fold(init.clone(), &fold)))
+            })
+            .collect()
+    }
+
+    /// Finds the docs matching every `(view, key)` lookup, in id order. A
+    /// lookup on a missing view matches nothing.
+    #[instrument]
+    pub fn find_by_views(&self, lookups: &[(&str, IndexKey)]) -> Vec<T> {
+        let mut matched: Option<BTreeSet<IndexKey>> = None;
+
+        for (name, lookup_key) in lookups {
+            let ids = self.views
+                .get(*name)
+                .map(|view| view.query(lookup_key))
+                .unwrap_or_default();
+
+            matched = Some(match matched {
+                Some(matched) => ids.into_iter()
+                    .filter(|id| matched.contains(id))
+                    .collect(),
+                None => ids.into_iter().collect(),
+            });
+        }
+
+        matched.unwrap_or_default()
+            .iter()
+            .flat_map(|key| self.get(key))
+            .flat_map(|doc| doc.obj)
+            .collect()
+    }
+
+    #[instrument(skip(range))]
+    pub fn find_by_view_range(
+        &self,
+        name: &str,
+        range: impl RangeBounds<IndexKey>
+    ) -> Vec<T> {
+        if let Some(view) = self.views.get(name) {
+            let keys = view.query_range(range);
+
+            keys.iter()
+                .flat_map(|key| self.get(key))
+                .flat_map(|doc| doc.obj.clone())
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+}
+
+
+impl <T: DocType> Drop for Mudb<T> {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            error!("failed to commit db changes on drop: {:?}", err);
+        }
+    }
+}
+
+impl <T: DocType> fmt::Debug for Mudb<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mudb")
+            .field("filename", &self.backing.as_ref().map(|b| &b.filename))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Result;
+    use cap_std::ambient_authority;
+    use cap_std::fs::Dir;
+    use cap_tempfile::TempDir;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+    use test_log::test;
+
+    const DATA_DIR: &str = ".data";
+
+    fn data_dir() -> Result<(TempDir, Dir)> {
+        let tmpd = TempDir::new(ambient_authority()).unwrap();
+        tmpd.create_dir(DATA_DIR)?;
+        let data = tmpd.open_dir(DATA_DIR)?;
+        Ok((tmpd, data))
+    }
+
+    fn msg_fixture() -> Vec<TestMessage> {
+        vec![
+            TestMessage::Of {
+                kind: 1,
+                val: "hello everyone".to_string(),
+            },
+            TestMessage::Of {
+                kind: 1,
+                val: "goodbye my friends".to_string(),
+            },
+            TestMessage::Empty {
+                kind: 0,
+            }
+        ]
+    }
+
+    type TestDb = (Mudb<TestMessage>, Vec<(VersionedKey, TestMessage)>);
+
+    fn init_db(
+        dd_rc: Arc<Dir>,
+        msgs: Option<Vec<TestMessage>>,
+        add_fixtures: bool,
+    ) -> Result<TestDb> {
+
+        let msgs = msgs.unwrap_or_else(msg_fixture);
+
+        let mut mudb = Mudb::<TestMessage>::open(
+            dd_rc.clone(),
+            "test.ndjson",
+        )?;
+
+        let results = if add_fixtures {
+            let view = View::<TestMessage>::new(
+                Box::new(MsgKindIndexer{})
+            );
+
+            mudb.views.insert(KString::from_static("kind"), view);
+
+            let results = msgs.iter().map(|msg| {
+                let key = mudb.insert(None, msg.clone()).unwrap();
+                (key, msg.clone())
+            }).collect();
+
+            mudb.build_views()?;
+            mudb.commit()?;
+            mudb.compact()?;
+
+            results
+        } else {
+            vec![]
+        };
+
+        Ok((mudb, results))
+    }
+
+    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Hash)]
+    enum TestMessage {
+        Empty { kind: u16, },
+        Of { kind: u16, val: String },
+    }
+
+    impl DocType for TestMessage {}
+
+    impl TestMessage {
+        fn val(&self) -> String {
+            match self {
+                TestMessage::Of { val, kind: _ } => format!("updated: {}", val),
+                TestMessage::Empty { kind: _ } => "new message".to_string(),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct MessageValQuery {
+        val: String,
+    }
+
+    impl <'a> Query<'a, TestMessage> for MessageValQuery {
+        fn matches(&self, obj: &'a TestMessage) -> bool {
+            match obj {
+                TestMessage::Empty { kind: _ } => false,
+                TestMessage::Of { kind: _, val } =>
+                    (*val).contains(&self.val),
+            }
+        }
+    }
+
+    fn val_filter(val: &str) -> MessageValQuery {
+        MessageValQuery {
+            val: val.to_string(),
+        }
+    }
+
+    fn val_field(msg: &TestMessage) -> Option<&str> {
+        match msg {
+            TestMessage::Of { kind: _, val } => Some(val),
+            TestMessage::Empty { kind: _ } => None,
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    struct MsgKindIndexer {}
+
+    impl Indexer<TestMessage> for MsgKindIndexer {
+        fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+            match msg {
+                TestMessage::Of { kind, val: _ } =>
+                    vec![IndexKey::Num(*kind as i64)],
+                _ => vec![],
+            }
+        }
+    }
+
+    // Matches messages of one kind, hinting the "kind" view and counting
+    // how many docs it was asked about.
+    #[derive(Debug)]
+    struct KindQuery {
+        kind: u16,
+        visited: std::cell::Cell<usize>,
+    }
+
+    impl <'a> Query<'a, TestMessage> for KindQuery {
+        fn matches(&self, msg: &TestMessage) -> bool {
+            self.visited.set(self.visited.get() + 1);
+            matches!(msg, TestMessage::Of { kind, val: _ } if *kind == self.kind)
+        }
+
+        fn index_hint(&self) -> Option<(KString, IndexKey)> {
+            Some((KString::from_static("kind"), IndexKey::Num(self.kind as i64)))
+        }
+    }
+
+    #[test]
+    fn basic_durability() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        let fixture = msg_fixture();
+        let key1 = {
+            let (db, msgs) = init_db(
+                dd_rc.clone(),
+                Some(fixture.clone()),
+                true
+            )?;
+
+            let (key1, msg1) = msgs.first().unwrap();
+            let (key2, msg2) = msgs.get(1).unwrap();
+
+            assert_eq!(
+                db.get(&key1.id()).and_then(|doc| doc.obj),
+                Some(msg1.clone())
+            );
+
+            assert_eq!(
+                db.get(&key2.id()).and_then(|doc| doc.obj),
+                Some(msg2.clone())
+            );
+
+            key1.clone()
+        };
+
+        {
+            let (mut db, _msgs) = init_db(dd_rc.clone(), Some(vec![]), true)?;
+            let msg1 = fixture.first().unwrap();
+            let msg2 = fixture.get(1).unwrap();
+
+            assert_eq!(
+                db.get(&key1.id()).and_then(|doc| doc.obj),
+                Some(msg1.clone())
+            );
+
+            let key3 = db.insert(Some(key1.clone()), msg2.clone())?;
+
+            assert_eq!(key3.id(), key1.id());
+            assert!(key3 != key1);
+            assert_eq!(
+                db.get(&key1.id()).and_then(|doc| doc.obj),
+                Some(msg2.clone())
+            );
+
+            assert_eq!(db.count(), fixture.len());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let tuple_id = || IndexKey::Tuple(vec![IndexKey::Num(9), IndexKey::Str(KString::from_static("x"))]);
+
+        let keys = {
+            let mut db = Mudb::<TestMessage>::open_with_codec(
+                dd_rc.clone(),
+                "test.bin",
+                Box::new(Bincode),
+            )?;
+
+            let mut keys = vec![
+                db.insert(Some(VersionedKey::new(IndexKey::Num(7))), fixture[0].clone())?,
+            ];
+            for msg in &fixture[1..] {
+                keys.push(db.insert(None, msg.clone())?);
+            }
+            db.insert_binary(IndexKey::Num(8), vec![0, 159, 146, 150])?;
+            db.insert(Some(VersionedKey::new(tuple_id())), fixture[0].clone())?;
+            db.commit()?;
+
+            keys
+        };
+
+        let db = Mudb::<TestMessage>::open_with_codec(
+            dd_rc.clone(),
+            "test.bin",
+            Box::new(Bincode),
+        )?;
+
+        assert_eq!(db.count(), fixture.len() + 2);
+        for (key, msg) in keys.iter().zip(fixture.iter()) {
+            assert_eq!(db.exact(key).and_then(|doc| doc.obj), Some(msg.clone()));
+        }
+        assert_eq!(db.get_binary(&IndexKey::Num(8)), Some(vec![0, 159, 146, 150]));
+        assert_eq!(db.get(&tuple_id()).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        drop(db);
+
+        assert!(matches!(
+            Mudb::<TestMessage>::open(dd_rc, "test.bin"),
+            Err(MudbError::Deserialize { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn compressed_round_trip(compression: Compression) -> Result<Vec<u8>> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = || MudbOptions::<TestMessage> {
+            compression,
+            ..MudbOptions::default()
+        };
+
+        let keys = {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.z", options())?;
+
+            // one frame per commit
+            let mut keys = vec![];
+            for msg in &fixture {
+                keys.push(db.insert(None, msg.clone())?);
+                db.commit()?;
+            }
+
+            keys
+        };
+
+        let db = Mudb::open_with_options(dd_rc.clone(), "test.z", options())?;
+
+        assert_eq!(db.count(), fixture.len());
+        for (key, msg) in keys.iter().zip(fixture.iter()) {
+            assert_eq!(db.exact(key).and_then(|doc| doc.obj), Some(msg.clone()));
+        }
+
+        Ok(dd_rc.read("test.z")?)
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trip() -> Result<()> {
+        let raw = compressed_round_trip(Compression::Gzip)?;
+        assert_eq!(&raw[..2], &[0x1f, 0x8b]);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() -> Result<()> {
+        let raw = compressed_round_trip(Compression::Zstd)?;
+        assert_eq!(&raw[..4], &[0x28, 0xb5, 0x2f, 0xfd]);
+        Ok(())
+    }
+
+    #[test]
+    fn deserialize_error_line() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let line = |id: i64, msg: &TestMessage| -> Result<String> {
+            let key = VersionedKey::new(IndexKey::Num(id)).incr();
+            let doc = Doc::new(key, Some(msg.clone()));
+            Ok(serde_json::to_string(&doc)?)
+        };
+
+        let contents = format!(
+            "{}\n{}\n{}\n",
+            line(1, &fixture[0])?,
+            "{\"key\": garbage",
+            line(2, &fixture[1])?,
+        );
+        dd_rc.write("test.ndjson", contents)?;
+
+        match Mudb::<TestMessage>::open(dd_rc, "test.ndjson") {
+            Err(MudbError::Deserialize { line, raw, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(raw.as_deref(), Some("{\"key\": garbage\n"));
+            },
+            other => panic!("expected a deserialize error, got {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn skip_corrupt() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        drop(db);
+
+        let corrupt = "{\"key\":{\"id\":\"01G";
+        let mut contents = dd_rc.read_to_string("test.ndjson")?;
+        contents.push_str(corrupt);
+        dd_rc.write("test.ndjson", contents)?;
+
+        assert!(matches!(
+            Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson"),
+            Err(MudbError::Deserialize { line: 4, .. })
+        ));
+
+        let skip = || MudbOptions::<TestMessage> {
+            on_corrupt: CorruptPolicy::SkipAndLog,
+            ..MudbOptions::default()
+        };
+
+        {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.ndjson", skip())?;
+            assert_eq!(db.skipped(), 1);
+            assert_eq!(db.count(), msgs.len());
+            for (key, msg) in &msgs {
+                assert_eq!(db.exact(key).and_then(|doc| doc.obj), Some(msg.clone()));
+            }
+            assert!(dd_rc.read_to_string("test.ndjson")?.contains(corrupt));
+
+            db.insert(None, msgs[0].1.clone())?;
+            db.commit()?;
+        }
+
+        // The bad record is still there, and the append after it survived.
+        assert!(dd_rc.read_to_string("test.ndjson")?.lines().any(|line| line == corrupt));
+        let db = Mudb::open_with_options(dd_rc, "test.ndjson", skip())?;
+        assert_eq!(db.skipped(), 1);
+        assert_eq!(db.count(), msgs.len() + 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn skip_corrupt_bincode() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let options = |on_corrupt| MudbOptions::<TestMessage> {
+            codec: Box::new(Bincode),
+            on_corrupt,
+            ..MudbOptions::default()
+        };
+
+        {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.bin", options(CorruptPolicy::Abort))?;
+            db.insert(Some(VersionedKey::new(IndexKey::Num(1))), fixture[0].clone())?;
+            db.commit()?;
+            db.insert(Some(VersionedKey::new(IndexKey::Num(2))), fixture[1].clone())?;
+            db.commit()?;
+        }
+
+        // Cut the last record short, as a crash mid-commit would.
+        let mut raw = dd_rc.read("test.bin")?;
+        raw.truncate(raw.len() - 3);
+        dd_rc.write("test.bin", raw)?;
+
+        {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.bin", options(CorruptPolicy::SkipAndLog))?;
+            assert_eq!(db.skipped(), 1);
+            db.insert(Some(VersionedKey::new(IndexKey::Num(3))), fixture[2].clone())?;
+            db.commit()?;
+            db.flush()?;
+        }
+
+        // Bincode can't read past a bad record, so the write after the
+        // skip only survives because open repaired the file first.
+        let db = Mudb::open_with_options(dd_rc, "test.bin", options(CorruptPolicy::Abort))?;
+        assert_eq!(db.get(&IndexKey::Num(1)).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        assert_eq!(db.get(&IndexKey::Num(2)), None);
+        assert_eq!(db.get(&IndexKey::Num(3)).and_then(|doc| doc.obj), Some(fixture[2].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn repair_on_open() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        drop(db);
+
+        let mut contents = dd_rc.read_to_string("test.ndjson")?;
+        contents.push_str("{\"key\":{\"id\":\"01G");
+        dd_rc.write("test.ndjson", contents)?;
+
+        {
+            let db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+                .on_corrupt(CorruptPolicy::SkipAndLog)
+                .repair_on_open(true)
+                .open()?;
+            assert_eq!(db.skipped(), 1);
+        }
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.skipped(), 0);
+        assert_eq!(db.count(), msgs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn contains() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+
+        assert!(db.contains(&key1.id()));
+        assert!(db.contains_exact(key1));
+        assert!(!db.contains(&IndexKey::Num(404)));
+
+        db.delete(key1.clone())?;
+        assert!(!db.contains(&key1.id()));
+        assert!(db.contains_exact(&key1.incr()));
+        assert!(!db.contains_exact(key1));
+
+        assert!(db.contains(&key2.id()));
+        assert!(!db.contains_exact(&key2.incr()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_many() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        let (key3, msg3) = msgs.get(2).unwrap();
+        let missing = IndexKey::Num(404);
+
+        db.delete(key2.clone())?;
+
+        let found = db.get_many(&[key1.id(), key2.id(), key3.id(), missing]);
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[&key1.id()].obj, Some(msg1.clone()));
+        assert_eq!(found[&key3.id()].obj, Some(msg3.clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn upsert() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (_key1, msg1) = msgs.first().unwrap();
+        let (_key2, msg2) = msgs.get(1).unwrap();
+        let id = IndexKey::Str(KString::from_static("upserted"));
+
+        let (key, existed) = db.upsert(id.clone(), msg1.clone())?;
+        assert!(!existed);
+        assert_eq!(key, VersionedKey::new(id.clone()).incr());
+
+        let (key, existed) = db.upsert(id.clone(), msg2.clone())?;
+        assert!(existed);
+        assert_eq!(key.ver, 2);
+        assert_eq!(db.get(&id).and_then(|doc| doc.obj), Some(msg2.clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn versioned_key_at() -> Result<()> {
+        let mut db = Mudb::<TestMessage>::in_memory();
+        let fixture = msg_fixture();
+        let id = IndexKey::Num(1);
+
+        let mut key = VersionedKey::new(id.clone());
+        for _ in 0..5 {
+            key = db.insert(Some(key), fixture[0].clone())?;
+        }
+
+        let at5 = VersionedKey::at(id.clone(), 5);
+        assert_eq!(at5, key);
+        assert_eq!(at5.ver(), 5);
+        assert_eq!(at5.id(), id);
+        assert_eq!(db.get_version(&id, at5.ver()).map(|doc| doc.key), Some(at5.clone()));
+        assert_eq!(db.exact(&at5).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+
+        // Same wire format as a key built any other way.
+        let json = serde_json::to_string(&at5)?;
+        assert_eq!(json, r#"{"id":1,"ver":5}"#);
+        assert_eq!(serde_json::from_str::<VersionedKey>(&json)?, at5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn history() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let keys = {
+            let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+            let (key1, _msg1) = msgs.first().unwrap();
+
+            let key2 = db.insert(Some(key1.clone()), fixture[1].clone())?;
+            db.commit()?;
+            let key3 = db.insert(Some(key2.clone()), fixture[2].clone())?;
+            db.commit()?;
+
+            // only the newest write is kept in memory
+            assert_eq!(db.history(&key1.id()).len(), 1);
+
+            vec![key1.clone(), key2, key3]
+        };
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        let history = db.history(&keys[0].id());
+
+        let found: Vec<(VersionedKey, TestMessage)> = history.into_iter()
+            .map(|doc| (doc.key, doc.obj.unwrap()))
+            .collect();
+        let expected: Vec<(VersionedKey, TestMessage)> = keys.into_iter()
+            .zip(fixture)
+            .collect();
+
+        assert_eq!(found, expected);
+        assert!(db.history(&IndexKey::Num(404)).is_empty());
+
+        let id = expected[0].0.id();
+        assert_eq!(db.get_version(&id, 1).and_then(|doc| doc.obj), Some(expected[0].1.clone()));
+        assert_eq!(db.get_version(&id, 2).and_then(|doc| doc.obj), Some(expected[1].1.clone()));
+        assert!(db.get_version(&id, 4).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn timestamps() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let first = db.get(&key1.id()).unwrap();
+        assert!(first.created_at().is_some());
+        assert_eq!(first.created_at(), first.updated_at());
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        db.insert(Some(key1.clone()), msg1.clone())?;
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        let second = db.get(&key1.id()).unwrap();
+        assert_eq!(second.created_at(), first.created_at());
+        assert!(second.updated_at() > first.updated_at());
+        drop(db);
+
+        // docs written without timestamps still load
+        let key = VersionedKey::new(IndexKey::Num(1)).incr();
+        dd_rc.write(
+            "old.ndjson",
+            format!("{{\"key\":{},\"flags\":[],\"obj\":null}}\n", serde_json::to_string(&key)?),
+        )?;
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "old.ndjson")?;
+        let old = db.exact(&key).unwrap();
+        assert_eq!(old.created_at(), None);
+        assert_eq!(old.updated_at(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn sweep_expired() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = MudbOptions::<TestMessage> {
+            ttl: Some(Duration::from_millis(20)),
+            ..MudbOptions::default()
+        };
+
+        let mut db = Mudb::open_with_options(dd_rc, "test.ndjson", options)?;
+        db.add_view(&KString::from_static("kind"), Box::new(MsgKindIndexer {}))?;
+
+        let old = db.insert(None, fixture[0].clone())?;
+        assert_eq!(db.sweep_expired()?, 0);
+
+        std::thread::sleep(Duration::from_millis(40));
+        let fresh = db.insert(None, fixture[1].clone())?;
+
+        assert_eq!(db.sweep_expired()?, 1);
+        db.build_views()?;
+
+        assert!(db.get_raw(&old.id()).unwrap().has_flag(&Flag::Deleted));
+        assert!(db.find(&val_filter("hello")).is_empty());
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)), vec![fixture[1].clone()]);
+        assert_eq!(db.get(&fresh.id()).and_then(|doc| doc.obj), Some(fixture[1].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn binary() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let bytes: Vec<u8> = (0..=255).rev().collect();
+        let id = IndexKey::Str(KString::from_static("blob"));
+
+        {
+            let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+            let key = db.insert_binary(id.clone(), bytes.clone())?;
+
+            assert!(db.get(&id).unwrap().has_flag(&Flag::Binary));
+            assert_eq!(db.get_binary(&id), Some(bytes.clone()));
+            assert_eq!(db.iter().count(), msgs.len());
+            assert_eq!(db.len(), msgs.len());
+            assert_eq!(db.summary().live, msgs.len());
+            assert_eq!(db.summary().binary, 1);
+
+            // a typed write over a blob clears the flag
+            let (_key1, msg1) = msgs.first().unwrap();
+            db.insert(Some(key), msg1.clone())?;
+            assert!(!db.get(&id).unwrap().has_flag(&Flag::Binary));
+            assert_eq!(db.get_binary(&id), None);
+
+            db.insert_binary(id.clone(), bytes.clone())?;
+        }
+
+        let raw = dd_rc.read_to_string("test.ndjson")?;
+        assert!(raw.contains(&base64::encode(&bytes)));
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.get_binary(&id), Some(bytes));
+
+        Ok(())
+    }
+
+    #[test]
+    fn validator() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        db.set_validator(Box::new(|msg: &TestMessage| match msg {
+            TestMessage::Of { val, kind: _ } if val.is_empty() => {
+                Err(MudbError::Invalid("empty message".to_string()))
+            },
+            _ => Ok(()),
+        }));
+
+        let empty = TestMessage::Of {
+            kind: 1,
+            val: "".to_string(),
+        };
+
+        assert!(matches!(db.insert(None, empty.clone()), Err(MudbError::Invalid(_))));
+        assert_eq!(db.count(), msgs.len());
+        assert!(!db.modified());
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let empty_op = {
+            let empty = empty.clone();
+            Box::new(move |_: &TestMessage| empty)
+        };
+        assert!(matches!(db.update(key1, empty_op), Some(Err(MudbError::Invalid(_)))));
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+        assert_eq!(db.commit()?, 0);
+
+        db.insert(None, TestMessage::Empty { kind: 0 })?;
+        assert_eq!(db.count(), msgs.len() + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_prunes_versions() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let id = IndexKey::Num(1);
+
+        {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            for msg in &fixture {
+                db.insert(Some(VersionedKey::new(id.clone())), msg.clone())?;
+                db.commit()?;
+            }
+            db.insert(None, fixture[0].clone())?;
+            db.commit()?;
+        }
+
+        {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            assert_eq!(db.history(&id).len(), 3);
+
+            let stats = db.compact()?;
+            assert_eq!(stats.docs_dropped, 2);
+            assert!(stats.bytes_after < stats.bytes_before);
+            assert_eq!(db.history(&id).len(), 1);
+        }
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), 2);
+        assert_eq!(
+            db.history(&id).into_iter().map(|doc| doc.key).collect::<Vec<_>>(),
+            vec![VersionedKey { id, ver: 3 }]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn len_and_is_empty() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "empty.ndjson")?;
+        assert!(db.is_empty());
+        assert_eq!(db.len(), 0);
+
+        let fixture = msg_fixture();
+        let key = db.insert(None, fixture[0].clone())?;
+        db.delete(key)?;
+        assert!(db.is_empty());
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.count(), 1);
+        drop(db);
+
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        assert!(!db.is_empty());
+        assert_eq!(db.len(), msgs.len());
+
+        let (key1, msg1) = msgs.first().unwrap();
+        db.insert(Some(key1.clone()), msg1.clone())?;
+        db.delete(msgs[1].0.clone())?;
+        assert_eq!(db.len(), msgs.len() - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_compact_ratio() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc, "test.ndjson")
+            .auto_compact_ratio(0.7)
+            .open()?;
+
+        let mut keys = vec![];
+        for i in 0..4 {
+            keys.push(db.insert(None, fixture[i % 2].clone())?);
+        }
+        db.commit()?;
+        assert_eq!(db.backing.as_ref().unwrap().records, 4);
+
+        // Each delete leaves a tombstone and the version it supersedes, so
+        // 4 dead of 6 records stays under the ratio...
+        db.delete(keys[0].clone())?;
+        db.commit()?;
+        db.delete(keys[1].clone())?;
+        db.commit()?;
+        assert_eq!(db.backing.as_ref().unwrap().records, 6);
+        assert_eq!(db.count(), 4);
+
+        // ...but 6 dead of 7 goes over, and compaction drops the tombstones.
+        db.delete(keys[2].clone())?;
+        db.commit()?;
+        assert_eq!(db.backing.as_ref().unwrap().records, 1);
+        assert_eq!(db.count(), 1);
+        assert_eq!(db.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_compact_ratio_counts_blobs() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc, "test.ndjson")
+            .auto_compact_ratio(0.5)
+            .open()?;
+
+        for i in 0..4 {
+            db.insert_binary(IndexKey::Num(i), vec![i as u8; 8])?;
+        }
+        db.commit()?;
+
+        // A typed doc written and deleted leaves 2 dead of 6 records, with
+        // the 4 blobs live, so the tombstone isn't compacted away.
+        let key = db.insert(None, msg_fixture()[0].clone())?;
+        db.commit()?;
+        db.delete(key)?;
+        db.commit()?;
+        assert_eq!(db.backing.as_ref().unwrap().records, 6);
+        assert_eq!(db.summary().binary, 4);
+        assert_eq!(db.summary().deleted, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keep_latest_only() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let id = IndexKey::Num(1);
+
+        {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            for i in 0..5 {
+                db.insert(Some(VersionedKey::new(id.clone())), fixture[i % 2].clone())?;
+                db.commit()?;
+            }
+            db.insert(None, fixture[2].clone())?;
+        }
+
+        let db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        assert_eq!(db.history(&id).len(), 5);
+        drop(db);
+
+        let options = MudbOptions::<TestMessage> {
+            keep_latest_only: true,
+            ..MudbOptions::default()
+        };
+
+        let db = Mudb::open_with_options(dd_rc, "test.ndjson", options)?;
+        assert_eq!(db.count(), 2);
+        assert_eq!(db.history(&id).len(), 1);
+        assert_eq!(db.get(&id).map(|doc| doc.key), Some(VersionedKey { id, ver: 5 }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, Some(vec![]), false)?;
+        let fixture = msg_fixture();
+
+        let id = |name: &'static str| IndexKey::Str(KString::from_static(name));
+
+        db.upsert(id("post:1"), fixture[0].clone())?;
+        db.upsert(id("user:2"), fixture[1].clone())?;
+        db.upsert(id("user:1"), fixture[0].clone())?;
+        db.upsert(id("user:1"), fixture[2].clone())?;
+        db.upsert(id("user:3"), fixture[2].clone())?;
+        db.upsert(id("users"), fixture[2].clone())?;
+        db.delete(db.get(&id("user:3")).unwrap().key)?;
+
+        assert_eq!(db.scan_prefix("user:"), vec![
+            (id("user:1"), fixture[2].clone()),
+            (id("user:2"), fixture[1].clone()),
+        ]);
+        assert_eq!(db.scan_prefix("post:").len(), 1);
+        assert!(db.scan_prefix("nonesuch").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn versioning() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let init = db.get(&key1.id).unwrap().obj.unwrap();
+        assert_eq!(init, msg1.clone());
+
+        let key2 = db.update(
+            key1,
+            Box::new(|msg: &TestMessage| msg.clone())
+        ).unwrap()?;
+        assert_eq!(key2.id, key1.id);
+        assert!(key2.ver > key1.ver);
+        assert_eq!(key1.incr(), key2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn id_generator() -> Result<()> {
+        #[derive(Debug, Default)]
+        struct Counter(i64);
+
+        impl IdGenerator for Counter {
+            fn generate(&mut self) -> IndexKey {
+                self.0 += 1;
+                IndexKey::Str(KString::from(format!("msg-{}", self.0)))
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = MudbOptions::<TestMessage> {
+            id_generator: Box::new(Counter::default()),
+            ..MudbOptions::default()
+        };
+
+        let mut db = Mudb::open_with_options(dd_rc, "test.ndjson", options)?;
+        let key1 = db.insert(None, fixture[0].clone())?;
+        let key2 = db.insert(None, fixture[1].clone())?;
+
+        assert_eq!(key1.id(), IndexKey::Str(KString::from_static("msg-1")));
+        assert_eq!(key2.id(), IndexKey::Str(KString::from_static("msg-2")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn auto_increment() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = || MudbOptions::<TestMessage> {
+            id_generator: Box::new(AutoIncrement::default()),
+            ..MudbOptions::default()
+        };
+
+        {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.ndjson", options())?;
+            for (i, msg) in fixture.iter().enumerate() {
+                let key = db.insert(None, msg.clone())?;
+                assert_eq!(key.id(), IndexKey::Num(i as i64 + 1));
+            }
+            db.commit()?;
+        }
+
+        let mut db = Mudb::open_with_options(dd_rc, "test.ndjson", options())?;
+        let key = db.insert(None, fixture[0].clone())?;
+        assert_eq!(key.id(), IndexKey::Num(4));
+
+        db.insert(Some(VersionedKey::new(IndexKey::Num(10))), fixture[1].clone())?;
+        let key = db.insert(None, fixture[2].clone())?;
+        assert_eq!(key.id(), IndexKey::Num(11));
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_many() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, Some(vec![]), false)?;
+
+        let fixture = msg_fixture();
+        let items = fixture.iter()
+            .map(|msg| (None, msg.clone()))
+            .collect();
+
+        let keys = db.insert_many(items)?;
+        assert_eq!(keys.len(), fixture.len());
+        assert!(db.modified());
+
+        for (key, msg) in keys.iter().zip(fixture.iter()) {
+            assert_eq!(key.ver, 1);
+            assert_eq!(db.exact(key).and_then(|doc| doc.obj), Some(msg.clone()));
+        }
+
+        assert_eq!(db.commit()?, fixture.len());
+
+        let updated = db.insert_many(vec![
+            (Some(keys[0].clone()), fixture[1].clone()),
+        ])?;
+        assert_eq!(updated, vec![keys[0].incr()]);
+        assert_eq!(
+            db.get(&keys[0].id()).and_then(|doc| doc.obj),
+            Some(fixture[1].clone())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn iter() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.get(1).unwrap();
+        db.delete(key1.clone())?;
+
+        let mut expected: Vec<(VersionedKey, TestMessage)> = msgs.iter()
+            .filter(|(key, _msg)| key != key1)
+            .cloned()
+            .collect();
+        expected.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let found: Vec<(VersionedKey, TestMessage)> = db.iter()
+            .map(|(key, msg)| (key, msg.clone()))
+            .collect();
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn version_conflict() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let key2 = db.insert(Some(key1.clone()), msg1.clone())?;
+
+        match db.insert(Some(key1.clone()), msg1.clone()) {
+            Err(MudbError::VersionConflict { expected, found }) => {
+                assert_eq!(&expected, key1);
+                assert_eq!(found, key2);
+            },
+            other => panic!("expected a version conflict, got {:?}", other),
+        }
+
+        let key3 = db.insert(Some(VersionedKey::new(key1.id())), msg1.clone())?;
+        assert_eq!(key3, key2.incr());
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_stale_key() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let (key1, key2) = {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            let key1 = db.insert(None, fixture[0].clone())?;
+            db.commit()?;
+            let key2 = db.insert(Some(key1.clone()), fixture[1].clone())?;
+            db.commit()?;
+            (key1, key2)
+        };
+
+        // Both versions are loaded, so the stale one can still be found.
+        let mut db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.history(&key1.id()).len(), 2);
+
+        let is_conflict = |result: Option<crate::Result<VersionedKey>>| matches!(
+            result,
+            Some(Err(MudbError::VersionConflict { expected, found }))
+                if expected == key1 && found == key2
+        );
+
+        assert!(matches!(db.delete(key1.clone()), Err(MudbError::VersionConflict { .. })));
+        assert!(is_conflict(db.update_maybe(&key1, Box::new(|_msg| None))));
+        assert!(is_conflict(db.update_maybe(&key1, Box::new(|msg| Some(msg.clone())))));
+
+        let doc = db.get(&key1.id()).unwrap();
+        assert_eq!(doc.key(), &key2);
+        assert_eq!(doc.obj, Some(fixture[1].clone()));
+        assert!(!db.modified());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rollback() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+
+        let added = db.insert(None, msg1.clone())?;
+        db.insert(Some(key1.clone()), msg2.clone())?;
+        db.delete(key2.clone())?;
+        db.build_views()?;
+        assert_eq!(db.count(), msgs.len() + 1);
+
+        db.rollback()?;
+        assert!(!db.modified());
+        assert_eq!(db.count(), msgs.len());
+        assert!(db.get(&added.id()).is_none());
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+        assert_eq!(db.get(&key2.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)).len(), 2);
+        assert_eq!(db.commit()?, 0);
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), msgs.len());
+        assert!(db.get(&added.id()).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn autocommit() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = MudbOptions::<TestMessage> {
+            autocommit_threshold: Some(2),
+            ..MudbOptions::default()
+        };
+
+        let mut db = Mudb::open_with_options(dd_rc.clone(), "test.ndjson", options)?;
+        for msg in &fixture {
+            db.insert(None, msg.clone())?;
+        }
+
+        assert!(db.modified());
+        assert_eq!(dd_rc.read_to_string("test.ndjson")?.lines().count(), 2);
+
+        assert_eq!(db.commit()?, 1);
+        assert_eq!(dd_rc.read_to_string("test.ndjson")?.lines().count(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fsync() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = || MudbOptions::<TestMessage> {
+            durability: Durability::Fsync,
+            ..MudbOptions::default()
+        };
+
+        let key = {
+            let mut db = Mudb::open_with_options(dd_rc.clone(), "test.ndjson", options())?;
+            let key = db.insert(None, fixture[0].clone())?;
+            assert_eq!(db.commit()?, 1);
+
+            db.delete(key.clone())?;
+            db.insert(None, fixture[1].clone())?;
+            db.compact()?;
+
+            key
+        };
+
+        let db = Mudb::open_with_options(dd_rc, "test.ndjson", options())?;
+        assert_eq!(db.count(), 2);
+        assert!(db.get_raw(&key.id()).unwrap().has_flag(&Flag::Deleted));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_stats() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let file_len = || -> Result<u64> { Ok(dd_rc.metadata("test.ndjson")?.len()) };
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+
+        let before = file_len()?;
+        db.insert(Some(key1.clone()), msg1.clone())?;
+        db.delete(key2.clone())?;
+
+        let stats = db.commit_stats()?;
+        let committed = file_len()?;
+        assert_eq!(stats.docs, 2);
+        assert_eq!(stats.bytes, committed - before);
+        assert!(stats.bytes > 0);
+
+        assert_eq!(db.commit_stats()?, CommitStats::default());
+
+        let stats = db.compact_with(CompactMode::DropTombstones)?;
+        assert_eq!(stats.docs_dropped, 1);
+        assert_eq!(stats.bytes_before, committed);
+        assert_eq!(stats.bytes_after, file_len()?);
+        assert!(stats.bytes_after < stats.bytes_before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn export() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        db.delete(key1.clone())?;
+
+        let mut out = vec![];
+        assert_eq!(db.export(&mut out)?, msgs.len() - 1);
+
+        let exported = out.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(serde_json::from_slice)
+            .collect::<std::result::Result<Vec<Doc<TestMessage>>, _>>()?;
+
+        let live: Vec<Doc<TestMessage>> = db.latest()
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .cloned()
+            .collect();
+
+        assert_eq!(exported, live);
+
+        Ok(())
+    }
+
+    #[test]
+    fn import() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        db.insert(Some(key1.clone()), msg1.clone())?;
+
+        let mut out = vec![];
+        db.export(&mut out)?;
+
+        let mut fresh = Mudb::<TestMessage>::open(dd_rc, "fresh.ndjson")?;
+        assert_eq!(fresh.import(out.as_slice())?, msgs.len());
+
+        let filt: QueryRef<'_, TestMessage> = &val_filter("");
+        assert_eq!(fresh.find(filt), db.find(filt));
+        assert!(fresh.iter().eq(db.iter()));
+        assert_eq!(fresh.get(&key1.id()), db.get(&key1.id()));
+
+        // only versions newer than what's stored are applied
+        assert_eq!(fresh.import(out.as_slice())?, 0);
+
+        let moved = TestMessage::Empty { kind: 9 };
+        fresh.insert(Some(key2.clone()), moved.clone())?;
+        fresh.insert(Some(key2.incr()), moved.clone())?;
+        assert_eq!(fresh.import(out.as_slice())?, 0);
+        assert_eq!(fresh.get(&key2.id()).and_then(|doc| doc.obj), Some(moved));
+
+        Ok(())
+    }
+
+    #[test]
+    fn backup() -> Result<()> {
+        let (_backup_tmp, backup_dir) = data_dir()?;
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let before = dd_rc.read("test.ndjson")?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+        db.delete(key1.clone())?;
+        let added = db.insert(None, msg2.clone())?;
+
+        db.backup(&backup_dir, "backup.ndjson")?;
+
+        // the live file hasn't been touched
+        assert_eq!(dd_rc.read("test.ndjson")?, before);
+        assert!(db.modified());
+
+        let copy = Mudb::<TestMessage>::open(Arc::new(backup_dir), "backup.ndjson")?;
+        assert_eq!(copy.count(), msgs.len());
+        assert!(copy.get(&key1.id()).is_none());
+        assert_eq!(copy.get(&key2.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+        assert_eq!(copy.get(&added.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        db.insert(Some(key1.clone()), msg1.clone())?;
+
+        db.clear()?;
+        assert_eq!(db.count(), 0);
+        assert!(!db.modified());
+        assert!(db.get(&key1.id()).is_none());
+        assert!(db.find_by_view("kind", IndexKey::Num(1)).is_empty());
+        drop(db);
+
+        assert_eq!(dd_rc.metadata("test.ndjson")?.len(), 0);
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn clear_notifies_committed() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        db.commit()?;
+
+        let events = db.subscribe();
+
+        let (key1, msg1) = msgs.first().unwrap();
+        db.insert(None, msg1.clone())?;
+        db.insert(Some(key1.clone()), msg1.clone())?;
+        db.clear()?;
+
+        let mut deleted: Vec<IndexKey> = events.try_iter()
+            .map(|event| match event {
+                ChangeEvent::Deleted(key) => key.id(),
+                other => panic!("expected only deletes, got {:?}", other),
+            })
+            .collect();
+        deleted.sort();
+
+        let mut committed: Vec<IndexKey> = msgs.iter().map(|(key, _msg)| key.id()).collect();
+        committed.sort();
+        assert_eq!(deleted, committed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_returning() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let shout = |msg: &TestMessage| TestMessage::Of {
+            kind: 1,
+            val: msg.val().to_uppercase(),
+        };
+
+        let (new_key, old) = db.update_returning(key1, Box::new(shout)).unwrap()?;
+        assert_eq!(new_key, key1.incr());
+        assert_eq!(&old, msg1);
+        assert_eq!(
+            db.get(&key1.id()).and_then(|doc| doc.obj),
+            Some(TestMessage::Of { kind: 1, val: msg1.val().to_uppercase() })
+        );
+
+        let (_key, old) = db.update_returning(&new_key, Box::new(shout)).unwrap()?;
+        assert_eq!(old, TestMessage::Of { kind: 1, val: msg1.val().to_uppercase() });
+
+        let missing = VersionedKey::new(IndexKey::Num(-1));
+        assert!(db.update_returning(&missing, Box::new(shout)).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_maybe() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+
+        let drop_hello = |msg: &TestMessage| match msg {
+            TestMessage::Of { kind: _, val } if val.contains("hello") => None,
+            other => Some(TestMessage::Of {
+                kind: 1,
+                val: other.val(),
+            }),
+        };
+
+        let deleted = db.update_maybe(key1, Box::new(drop_hello)).unwrap()?;
+        assert_eq!(deleted, key1.incr());
+        assert!(db.get(&key1.id()).and_then(|doc| doc.obj).is_none());
+        assert!(db.get_raw(&key1.id()).unwrap().has_flag(&Flag::Deleted));
+
+        let updated = db.update_maybe(key2, Box::new(drop_hello)).unwrap()?;
+        assert_eq!(
+            db.get(&updated.id()).and_then(|doc| doc.obj),
+            Some(TestMessage::Of { kind: 1, val: msg2.val() })
+        );
+
+        assert!(db.update_maybe(key1, Box::new(drop_hello)).is_none());
+        assert_eq!(db.commit()?, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn update_cas() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (_key2, msg2) = msgs.get(1).unwrap();
+
+        let replace = |msg: &TestMessage| -> Box<dyn FnOnce(&TestMessage) -> TestMessage> {
+            let msg = msg.clone();
+            Box::new(move |_| msg)
+        };
+
+        let key2 = db.update_cas(key1, replace(msg2))?;
+        assert_eq!(key2, key1.incr());
+
+        match db.update_cas(key1, replace(msg1)) {
+            Err(MudbError::VersionConflict { expected, found }) => {
+                assert_eq!(&expected, key1);
+                assert_eq!(found, key2);
+            },
+            other => panic!("expected a version conflict, got {:?}", other),
+        }
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+
+        let missing = VersionedKey::new(IndexKey::Num(404)).incr();
+        assert!(matches!(
+            db.update_cas(&missing, replace(msg1)),
+            Err(MudbError::VersionConflict { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        db.compact()?;
+        let (key1, msg1) = msgs.first().unwrap();
+
+        assert_eq!(db.count(), msgs.len());
+        assert_eq!(
+            db.get(&key1.id()).and_then(|doc| doc.obj),
+            Some(msg1.clone())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn commit_after_compact() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let fixture = msg_fixture();
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        db.delete(key1.clone())?;
+        db.compact_with(CompactMode::DropTombstones)?;
+
+        let key = db.insert(None, fixture[1].clone())?;
+        let stats = db.commit_stats()?;
+        assert_eq!(stats.docs, 1);
+        assert!(stats.bytes > 0);
+
+        // Read the file as the commit left it, before drop compacts again.
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.get(&key.id()).and_then(|doc| doc.obj), Some(fixture[1].clone()));
+        assert_eq!(reader.get_raw(&key1.id()), None);
+        assert_eq!(reader.len(), msgs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn compact_drop_tombstones() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+
+        db.delete(key1.clone())?;
+        db.delete(key2.clone())?;
+        db.compact()?;
+        assert!(db.get_raw(&key1.id()).unwrap().has_flag(&Flag::Deleted));
+
+        db.compact_with(CompactMode::DropTombstones)?;
+        assert_eq!(db.count(), msgs.len() - 2);
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), msgs.len() - 2);
+        assert!(db.get(&key1.id()).is_none());
+        assert!(db.data.keys().all(|key| key.id != key1.id && key.id != key2.id));
+
+        Ok(())
+    }
+
+    #[test]
+    fn update() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key, msg) = msgs.first().unwrap();
+
+        let kind = match msg {
+            TestMessage::Of { val: _, kind } => *kind,
+            TestMessage::Empty { kind } => *kind,
+        };
+        let updated_val = match msg {
+            TestMessage::Of { val, kind: _ } => format!(
+                "updated {}",
+                val
+            ),
+            _ => "".to_string(),
+        };
+
+        let op: Box<dyn FnOnce(&TestMessage) -> TestMessage> = {
+            let updated_val = updated_val.clone();
+            Box::new(move |_| TestMessage::Of {
+                val: updated_val,
+                kind
+            })
+        };
+
+        let idx = db.update(key, op)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(idx.clone(), key.incr());
+
+        let found = db.get(&idx.id()).unwrap();
+        assert_eq!(found.obj, Some(TestMessage::Of {
+            val: updated_val.clone(),
+            kind
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn filter() -> Result<()> {
+        let msgs = msg_fixture();
+        let msg1 = msgs.first().unwrap();
+        let msg2 = msgs.get(1).unwrap();
+
+        // basic filtering
+        let filt1: QueryRef<'_, TestMessage> = &val_filter("hello");
+        assert!(filt1.matches(msg1));
+        assert!(!filt1.matches(msg2));
+
+        let filt2: QueryRef<'_, TestMessage> = &val_filter("goodbye");
+        assert!(!filt2.matches(msg1));
+        assert!(filt2.matches(msg2));
+
+        // negation
+        assert!(!(!filt1).matches(msg1));
+        assert!((!filt2).matches(msg1));
+
+        // logical 'and'
+        assert!(!(filt1 & filt2).matches(msg1));
+
+        // logical 'or'
+        assert!((filt1 | filt2).matches(msg1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn boxed_query() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, None, true)?;
+
+        db.insert(None, TestMessage::Of {
+            kind: 2,
+            val: "see you later".to_string(),
+        })?;
+
+        let filters: Vec<BoxedQuery<TestMessage>> = ["hello", "later", "nonesuch"]
+            .iter()
+            .map(|val| BoxedQuery::new(val_filter(val)))
+            .collect();
+
+        let query = BoxedQuery::any(filters).unwrap();
+        let found = db.find(&query);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|msg| msg.val().contains("hello") || msg.val().contains("later")));
+
+        let query = !query & BoxedQuery::new(val_filter("goodbye"));
+        assert_eq!(db.find(&query).len(), 1);
+
+        assert!(BoxedQuery::<TestMessage>::all(vec![]).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc, None, true)?;
+
+        let filt: QueryRef<'_, TestMessage> = &val_filter("hello");
+
+        let (_key1, msg1) = msgs.first().unwrap();
+        let (_key2, msg2) = msgs.get(1).unwrap();
+
+        let found = db.find(filt);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found.first().unwrap(), &msg1.clone());
+
+        let inverse = !filt;
+        let found = db.find(&inverse);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().any(|msg| msg == msg2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn count_match() -> Result<()> {
+        #[derive(Debug, Default)]
+        struct CountingQuery {
+            calls: std::sync::atomic::AtomicUsize,
+        }
+
+        impl <'a> Query<'a, TestMessage> for CountingQuery {
+            fn matches(&self, _obj: &TestMessage) -> bool {
+                self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                true
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc, None, true)?;
+
+        let counting = CountingQuery::default();
+        assert!(db.any_match(&counting));
+        assert_eq!(counting.calls.into_inner(), 1);
+
+        let filt: QueryRef<'_, TestMessage> = &val_filter("hello");
+        assert!(!db.any_match(&val_filter("nonesuch")));
+        assert_eq!(db.count_match(filt), db.find(filt).len());
+        assert_eq!(db.count_match(&!filt), db.find(&!filt).len());
+        assert_eq!(db.count_match(&CountingQuery::default()), msgs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_limited() -> Result<()> {
+        #[derive(Debug, Default)]
+        struct CountingQuery {
+            calls: std::sync::atomic::AtomicUsize,
+        }
+
+        impl <'a> Query<'a, TestMessage> for CountingQuery {
+            fn matches(&self, _obj: &TestMessage) -> bool {
+                self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                true
+            }
+        }
+
+        let mut db = Mudb::<TestMessage>::in_memory();
+        for n in 0..1000 {
+            db.insert(None, TestMessage::Of {
+                kind: n % 3,
+                val: format!("message {}", n),
+            })?;
+        }
+
+        let counting = CountingQuery::default();
+        assert_eq!(db.find_limited(&counting, 10).len(), 10);
+        assert_eq!(counting.calls.into_inner(), 10);
+
+        assert_eq!(db.find_limited(&val_filter("message 99"), 100).len(), 11);
+        assert!(db.find_limited(&val_filter("nonesuch"), 10).is_empty());
+        assert!(db.find_limited(&CountingQuery::default(), 0).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_paged() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, None, true)?;
+
+        for i in 0..5 {
+            db.insert(None, TestMessage::Of {
+                kind: 2,
+                val: format!("page {}", i),
+            })?;
+        }
+
+        let filt: QueryRef<'_, TestMessage> = &val_filter("page");
+        let all = db.find_paged(filt, 0, usize::MAX);
+        assert_eq!(all.len(), 5);
+
+        let mut paged = vec![];
+        for offset in (0..6).step_by(2) {
+            let page = db.find_paged(filt, offset, 2);
+            assert_eq!(page, db.find_paged(filt, offset, 2));
+            assert_eq!(page.len(), if offset < 4 { 2 } else { 1 });
+            paged.extend(page);
+        }
+
+        assert_eq!(paged, all);
+        assert!(db.find_paged(filt, 5, 2).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_survives_reopen_without_compact() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let (key1, _msg1) = msgs.first().unwrap();
+
+        let lines_before = dd_rc.read_to_string("test.ndjson")?.lines().count();
+        db.delete(key1.clone())?;
+        db.commit()?;
+
+        // The tombstone was appended rather than compacted in.
+        let contents = dd_rc.read_to_string("test.ndjson")?;
+        assert_eq!(contents.lines().count(), lines_before + 1);
+
+        // A read-only handle doesn't need the lock, so it can load the file
+        // as it stands, before drop compacts it.
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.get(&key1.id()), None);
+        assert!(reader.get_raw(&key1.id()).is_some_and(|doc| doc.has_flag(&Flag::Deleted)));
+        assert!(!reader.contains(&key1.id()));
+        assert_eq!(reader.len(), msgs.len() - 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_all() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        db.commit()?;
+
+        let (key3, _msg3) = msgs.get(2).unwrap();
+        db.delete(key3.clone())?;
+
+        let suffixed = |msg: &TestMessage| match msg {
+            TestMessage::Of { kind, val } => TestMessage::Of {
+                kind: kind + 1,
+                val: format!("{}!", val),
+            },
+            other => other.clone(),
+        };
+
+        assert_eq!(db.map_all(suffixed)?, msgs.len() - 1);
+        assert_eq!(db.changed.len(), msgs.len());
+
+        for (key, msg) in &msgs[..2] {
+            let doc = db.get(&key.id()).unwrap();
+            assert_eq!(doc.key(), &key.incr());
+            assert_eq!(doc.obj, Some(suffixed(msg)));
+        }
+        assert_eq!(db.get(&key3.id()), None);
+
+        // Views follow the new values.
+        db.build_views()?;
+        assert!(db.find_by_view("kind", IndexKey::Num(1)).is_empty());
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(2)).len(), 2);
+
+        db.commit()?;
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        let (key1, msg1) = msgs.first().unwrap();
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(suffixed(msg1)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn map_all_stops_at_error() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        // Sequential ids, so docs are rewritten in fixture order.
+        let mut db = Mudb::<TestMessage>::builder(dd_rc, "test.ndjson")
+            .id_generator(Box::new(AutoIncrement::default()))
+            .open()?;
+        let mut msgs = vec![];
+        for msg in msg_fixture() {
+            msgs.push((db.insert(None, msg.clone())?, msg));
+        }
+        db.commit()?;
+
+        db.set_validator(Box::new(|msg: &TestMessage| match msg {
+            TestMessage::Of { val, kind: _ } if val.is_empty() => {
+                Err(MudbError::Invalid("empty message".to_string()))
+            },
+            _ => Ok(()),
+        }));
+
+        // Only the last doc, the `Empty` one, comes out invalid.
+        let rewrite = |msg: &TestMessage| match msg {
+            TestMessage::Of { kind, val } => TestMessage::Of {
+                kind: *kind,
+                val: format!("{}!", val),
+            },
+            TestMessage::Empty { kind } => TestMessage::Of {
+                kind: *kind,
+                val: "".to_string(),
+            },
+        };
+
+        assert!(matches!(db.map_all(rewrite), Err(MudbError::Invalid(_))));
+        assert_eq!(db.changed.len(), 2);
+
+        for (key, msg) in &msgs[..2] {
+            assert_eq!(db.get(&key.id()).and_then(|doc| doc.obj), Some(rewrite(msg)));
+        }
+        let (key3, msg3) = msgs.get(2).unwrap();
+        assert_eq!(db.get(&key3.id()).and_then(|doc| doc.obj), Some(msg3.clone()));
+        assert_eq!(db.get(&key3.id()).unwrap().key(), key3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn delete_by_query() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+
+        let filt = val_filter("hello");
+        assert_eq!(db.delete_by_query(&filt)?, 1);
+
+        assert!(db.find(&filt).is_empty());
+        assert!(db.get(&key1.id()).and_then(|doc| doc.obj).is_none());
+        assert!(db.get_raw(&key1.id()).unwrap().has_flag(&Flag::Deleted));
+        assert_eq!(db.get(&key2.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+
+        assert_eq!(db.commit()?, 1);
+        assert_eq!(db.delete_by_query(&filt)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn views() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (_key1, msg1) = msgs.first().unwrap();
+        let (_key2, msg2) = msgs.get(1).unwrap();
+
+        let results = db.find_by_view(
+            "kind",
+            IndexKey::Num(1)
+        );
+
+        assert_eq!(results.len(), 2);
+
+        let expected = HashSet::<TestMessage>::from(
+            [msg1.clone(), msg2.clone()]
+        );
+
+        let found = HashSet::<TestMessage>::from_iter(
+            results.iter().cloned()
+        );
+
+        assert_eq!(expected, found);
+
+        let results = db.find_by_view(
+            "kind",
+            IndexKey::Num(2)
+        );
+
+        assert_eq!(results.len(), 0);
+
+        let results = db.find_by_view(
+            "nonesuch",
+            IndexKey::Num(1)
+        );
+
+        assert_eq!(results.len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_view_keyed() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        db.update(key1, Box::new(|msg: &TestMessage| msg.clone())).unwrap()?;
+
+        let results = db.find_by_view_keyed("kind", IndexKey::Num(1));
+        assert_eq!(results.len(), db.find_by_view("kind", IndexKey::Num(1)).len());
+        assert_eq!(results.len(), 2);
+
+        for (key, obj) in &results {
+            assert_eq!(db.exact(key).and_then(|doc| doc.obj).as_ref(), Some(obj));
+        }
+        assert!(results.iter().any(|(key, _obj)| *key == key1.incr()));
+
+        assert!(db.find_by_view_keyed("kind", IndexKey::Num(2)).is_empty());
+        assert!(db.find_by_view_keyed("nonesuch", IndexKey::Num(1)).is_empty());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_build_views() -> Result<()> {
+        #[derive(Debug)]
+        struct ValLenIndexer;
+
+        impl Indexer<TestMessage> for ValLenIndexer {
+            fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+                match msg {
+                    TestMessage::Of { kind: _, val } => vec![IndexKey::Num(val.len() as i64)],
+                    _ => vec![],
+                }
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        let mut serial = Mudb::<TestMessage>::in_memory();
+
+        for (key, msg) in &msgs {
+            serial.insert(Some(VersionedKey::new(key.id())), msg.clone())?;
+        }
+        for target in [&mut db, &mut serial] {
+            target.add_view(&KString::from_static("len"), Box::new(ValLenIndexer))?;
+        }
+        serial.add_view(&KString::from_static("kind"), Box::new(MsgKindIndexer {}))?;
+
+        let check = |db: &Mudb<TestMessage>, serial: &Mudb<TestMessage>| {
+            for name in ["kind", "len"] {
+                assert_eq!(db.view_counts(name), serial.view_counts(name));
+                for key in db.view_counts(name).into_keys() {
+                    let mut found = db.find_by_view(name, key.clone());
+                    let mut expected = serial.find_by_view(name, key);
+                    found.sort_by_key(|msg| format!("{:?}", msg));
+                    expected.sort_by_key(|msg| format!("{:?}", msg));
+                    assert_eq!(found, expected);
+                }
+            }
+        };
+
+        db.par_build_views()?;
+        serial.build_views()?;
+        check(&db, &serial);
+
+        // Incremental builds still track each id's keys.
+        let (key1, _msg1) = msgs.first().unwrap();
+        let moved = TestMessage::Of { kind: 2, val: "hi".to_string() };
+        db.insert(Some(VersionedKey::new(key1.id())), moved.clone())?;
+        serial.insert(Some(VersionedKey::new(key1.id())), moved)?;
+        db.par_build_views()?;
+        serial.build_views()?;
+        check(&db, &serial);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_with_index_hint() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, None, true)?;
+        db.insert(None, TestMessage::Of { kind: 2, val: "elsewhere".to_string() })?;
+
+        let query = KindQuery { kind: 1, visited: Default::default() };
+        let scanned = db.find(&query);
+        assert_eq!(scanned.len(), 2);
+        assert_eq!(query.visited.get(), db.count());
+
+        // The view covers the latest insert only once it's rebuilt.
+        db.build_views()?;
+        query.visited.set(0);
+        assert_eq!(db.find(&query), scanned);
+        assert_eq!(query.visited.get(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_with_index_hint_skips_old_versions() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let msg = |kind, val: &str| TestMessage::Of { kind, val: val.to_string() };
+
+        let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        let kept = db.insert(None, msg(1, "draft"))?;
+        let moved = db.insert(None, msg(1, "moved"))?;
+        db.commit()?;
+        db.insert(Some(kept.clone()), msg(1, "final"))?;
+        db.insert(Some(moved), msg(2, "moved"))?;
+        db.commit()?;
+
+        // Read the file as the commits left it, before drop compacts.
+        let mut reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.history(&kept.id()).len(), 2);
+
+        let query = KindQuery { kind: 1, visited: Default::default() };
+        let scanned = reader.find(&query);
+        assert_eq!(scanned, vec![msg(1, "final")]);
+        assert_eq!(reader.count_match(&query), scanned.len());
+
+        reader.add_view(&KString::from_static("kind"), Box::new(MsgKindIndexer {}))?;
+        reader.build_views()?;
+        assert_eq!(reader.find(&query), scanned);
+
+        Ok(())
+    }
+
+    #[test]
+    fn schemaless_json() -> Result<()> {
+        use serde_json::json;
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        let key = {
+            let mut db = JsonMudb::open(dd_rc.clone(), "test.ndjson")?;
+            let key = db.insert(None, json!({ "name": "ada", "langs": ["rust", "ml"] }))?;
+            db.insert(None, json!({ "name": "bob", "age": 42 }))?;
+            db.insert(None, json!([1, 2, 3]))?;
+            db.commit()?;
+            key
+        };
+
+        let db = JsonMudb::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), 3);
+        assert_eq!(db.get(&key.id()).unwrap().obj().unwrap()["name"], "ada");
+
+        let named = db.find(&FnQuery(|obj: &serde_json::Value| obj.get("name").is_some()));
+        assert_eq!(named.len(), 2);
+
+        let adults = db.find(&FnQuery(|obj: &serde_json::Value| {
+            obj["age"].as_i64().is_some_and(|age| age >= 18)
+        }));
+        assert_eq!(adults, vec![json!({ "name": "bob", "age": 42 })]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn subscribe() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        db.commit()?;
+
+        let events = db.subscribe();
+        let dropped = db.subscribe();
+        drop(dropped);
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        let new_key = db.insert(None, msg1.clone())?;
+        let updated = db.insert(Some(key1.clone()), msg1.clone())?;
+        db.delete(key2.clone())?;
+        assert!(events.try_recv().is_err());
+
+        db.commit()?;
+        assert_eq!(events.try_recv()?, ChangeEvent::Inserted(new_key));
+        assert_eq!(events.try_recv()?, ChangeEvent::Updated(updated));
+        assert!(matches!(events.try_recv()?, ChangeEvent::Deleted(key) if key.id() == key2.id()));
+        assert!(events.try_recv().is_err());
+        assert_eq!(db.subscribers.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn replicate_changes() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let mut replica = Mudb::<TestMessage>::open(dd_rc, "replica.ndjson")?;
+
+        let changes = db.changes_since(0);
+        assert_eq!(changes.len(), msgs.len());
+        assert_eq!(replica.apply_changes(changes)?, msgs.len());
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        let msg = TestMessage::Of { kind: 3, val: "changed".to_string() };
+        db.insert(Some(key1.clone()), msg.clone())?;
+        db.delete(key2.clone())?;
+
+        let changes = db.changes_since(1);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(replica.apply_changes(changes.clone())?, 2);
+        assert_eq!(replica.apply_changes(changes)?, 0);
+
+        let collect = |db: &Mudb<TestMessage>| -> Vec<(VersionedKey, TestMessage)> {
+            db.iter().map(|(key, obj)| (key, obj.clone())).collect()
+        };
+        assert_eq!(collect(&replica), collect(&db));
+        assert!(replica.get_raw(&key2.id()).unwrap().has_flag(&Flag::Deleted));
+
+        Ok(())
+    }
+
+    #[test]
+    fn merge_from() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let mut shard = Mudb::<TestMessage>::open(dd_rc, "shard.ndjson")?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+        let (key3, msg3) = msgs.get(2).unwrap();
+
+        // key1 is newer in the shard, key2 newer locally, key3 deleted in
+        // the shard, and the shard has one id of its own.
+        let newer = TestMessage::Of { kind: 4, val: "from the shard".to_string() };
+        shard.insert(Some(key1.clone()), fixture[0].clone())?;
+        shard.insert(Some(key1.incr()), newer.clone())?;
+        shard.insert(Some(key2.clone()), fixture[0].clone())?;
+        db.insert(Some(key2.clone()), msg2.clone())?;
+        db.insert(Some(key2.incr()), msg2.clone())?;
+        shard.insert(Some(key3.clone()), msg3.clone())?;
+        shard.delete(key3.incr())?;
+        let own = shard.insert(None, fixture[1].clone())?;
+
+        assert_eq!(db.merge_from(&shard)?, 3);
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(newer));
+        assert_eq!(db.get(&key2.id()).map(|doc| doc.key.ver), Some(3));
+        assert!(db.get(&key3.id()).is_none());
+        assert_eq!(db.get(&own.id()).and_then(|doc| doc.obj), Some(fixture[1].clone()));
+
+        assert_eq!(db.merge_from(&shard)?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn conflict_resolver() -> Result<()> {
+        #[derive(Debug)]
+        struct Concat;
+
+        impl ConflictResolver<TestMessage> for Concat {
+            fn resolve(&self, local: &Doc<TestMessage>, incoming: &Doc<TestMessage>) -> Doc<TestMessage> {
+                match (local.obj(), incoming.obj()) {
+                    (
+                        Some(TestMessage::Of { kind, val: ours }),
+                        Some(TestMessage::Of { kind: _, val: theirs }),
+                    ) => Doc::new(incoming.key().clone(), Some(TestMessage::Of {
+                        kind: *kind,
+                        val: format!("{} / {}", ours, theirs),
+                    })),
+                    _ => LastVersionWins.resolve(local, incoming),
+                }
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+            .conflict_resolver(Box::new(Concat))
+            .open()?;
+        let mut other = Mudb::<TestMessage>::open(dd_rc, "other.ndjson")?;
+
+        let key = db.insert(None, fixture[0].clone())?;
+        other.insert(Some(VersionedKey::new(key.id())), fixture[1].clone())?;
+        let fresh = other.insert(None, fixture[2].clone())?;
+
+        assert_eq!(db.merge_from(&other)?, 2);
+        assert_eq!(
+            db.get(&key.id()).and_then(|doc| doc.obj),
+            Some(TestMessage::Of { kind: 1, val: "hello everyone / goodbye my friends".to_string() })
+        );
+        assert_eq!(db.get(&key.id()).map(|doc| doc.key.ver), Some(2));
+        assert_eq!(db.get(&fresh.id()).and_then(|doc| doc.obj), Some(fixture[2].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn scan_from() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        db.insert(Some(key1.clone()), msg1.clone())?;
+        let gone = db.insert(None, msg1.clone())?;
+        db.delete(gone)?;
+
+        let mut seen = vec![];
+        let mut cursor = None;
+        let mut pages = 0;
+
+        loop {
+            let (page, next) = db.scan_from(cursor, 1);
+            pages += 1;
+            seen.extend(page.into_iter().map(|(key, _obj)| key.id()));
+
+            match next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        let mut expected: Vec<IndexKey> = msgs.iter().map(|(key, _msg)| key.id()).collect();
+        expected.sort();
+        assert_eq!(seen, expected);
+        assert_eq!(pages, msgs.len());
+
+        let (all, next) = db.scan_from(None, 10);
+        assert_eq!(all.len(), msgs.len());
+        assert!(next.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_refs() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, _msgs) = init_db(dd_rc, None, true)?;
+
+        let filt = val_filter("o");
+        let found = db.find_refs(&filt);
+        let owned = db.find(&filt);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found.iter().copied().cloned().collect::<Vec<_>>(), owned);
+
+        // The refs point into the db itself.
+        for obj in found {
+            assert!(db.iter().any(|(_key, stored)| std::ptr::eq(stored, obj)));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn refresh() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        db.commit()?;
+
+        let mut reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.refresh()?, 0);
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let msg = TestMessage::Of { kind: 3, val: "appended".to_string() };
+        let new_key = db.insert(None, msg.clone())?;
+        let updated = db.insert(Some(key1.clone()), msg.clone())?;
+        db.commit()?;
+        assert_eq!(db.refresh()?, 0);
+
+        assert!(reader.get(&new_key.id()).is_none());
+        assert_eq!(reader.refresh()?, 2);
+        assert_eq!(reader.get(&new_key.id()).unwrap().obj(), Some(&msg));
+        assert_eq!(reader.get(&key1.id()).unwrap().key(), &updated);
+        assert_eq!(reader.refresh()?, 0);
+
+        // A compacted file is read again from the start.
+        db.delete(new_key.clone())?;
+        db.compact()?;
+        assert_eq!(reader.refresh()?, 1);
+        assert!(reader.get_raw(&new_key.id()).unwrap().has_flag(&Flag::Deleted));
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffered_commits_and_flush() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = MudbOptions::<TestMessage> {
+            durability: Durability::None,
+            ..MudbOptions::default()
+        };
+        let mut db = Mudb::open_with_options(dd_rc.clone(), "test.ndjson", options)?;
+
+        let mut keys = vec![];
+        for msg in &fixture {
+            keys.push(db.insert(None, msg.clone())?);
+            db.commit()?;
+        }
+
+        let on_disk = || -> Result<usize> {
+            Ok(Mudb::<TestMessage>::open_read_only(dd_rc.clone(), "test.ndjson")?.count())
+        };
+        assert_eq!(on_disk()?, 0);
+
+        db.flush()?;
+        assert_eq!(on_disk()?, fixture.len());
+
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc.clone(), "test.ndjson")?;
+        for (key, msg) in keys.iter().zip(&fixture) {
+            assert_eq!(reader.get(&key.id()).unwrap().obj(), Some(msg));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn first_last_recent() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let options = MudbOptions::<TestMessage> {
+            id_generator: Box::new(AutoIncrement::default()),
+            ..MudbOptions::default()
+        };
+        let mut db = Mudb::open_with_options(dd_rc, "test.ndjson", options)?;
+        assert_eq!(db.first(), None);
+        assert!(db.recent(2).is_empty());
+
+        let mut keys = vec![];
+        for msg in &fixture {
+            keys.push(db.insert(None, msg.clone())?);
+        }
+        // A newer version of the first doc doesn't move it.
+        db.insert(Some(keys[0].clone()), fixture[0].clone())?;
+
+        assert_eq!(db.first(), Some((IndexKey::Num(1), fixture[0].clone())));
+        assert_eq!(db.last(), Some((IndexKey::Num(3), fixture[2].clone())));
+        assert_eq!(db.recent(2), vec![fixture[2].clone(), fixture[1].clone()]);
+        assert_eq!(db.recent(10).len(), fixture.len());
+
+        db.delete(keys[2].clone())?;
+        assert_eq!(db.last(), Some((IndexKey::Num(2), fixture[1].clone())));
+        assert_eq!(db.recent(1), vec![fixture[1].clone()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn builder() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        {
+            let mut db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+                .id_generator(Box::new(AutoIncrement::default()))
+                .autocommit_threshold(2)
+                .open()?;
+
+            for msg in &fixture {
+                db.insert(None, msg.clone())?;
+            }
+            assert_eq!(db.changed.len(), 1);
+            assert_eq!(db.first(), Some((IndexKey::Num(1), fixture[0].clone())));
+        }
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc, "test.ndjson")
+            .read_only(true)
+            .open()?;
+        assert_eq!(db.count(), fixture.len());
+        assert!(matches!(db.insert(None, fixture[0].clone()), Err(MudbError::ReadOnly)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_through() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+            .write_through(true)
+            .open()?;
+
+        let key1 = db.insert(None, fixture[0].clone())?;
+        let key2 = db.insert(None, fixture[1].clone())?;
+        assert!(db.changed.is_empty());
+        assert!(!db.modified());
+        db.delete(key2.clone())?;
+
+        // Everything is on disk without a commit.
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.get(&key1.id()).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        assert!(reader.get_raw(&key2.id()).is_some_and(|doc| doc.has_flag(&Flag::Deleted)));
+
+        assert_eq!(db.commit()?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_through_update() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+            .write_through(true)
+            .open()?;
+
+        let key = db.insert(None, fixture[0].clone())?;
+        let replacement = fixture[1].clone();
+        db.update(&key, Box::new(move |_msg| replacement)).unwrap()?;
+        assert!(db.changed.is_empty());
+        assert!(!db.modified());
+        assert_eq!(db.commit()?, 0);
+
+        // One record per write, the update's being the one that wins.
+        assert_eq!(dd_rc.read_to_string("test.ndjson")?.lines().count(), 2);
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        assert_eq!(reader.get(&key.id()).and_then(|doc| doc.obj), Some(fixture[1].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn json_array_format() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let open = || {
+            Mudb::<TestMessage>::builder(dd_rc.clone(), "test.json")
+                .format(Format::JsonArray)
+                .open()
+        };
+
+        let deleted = {
+            let mut db = open()?;
+            let mut keys = vec![];
+            for msg in &fixture {
+                keys.push(db.insert(None, msg.clone())?);
+            }
+            let len_before = dd_rc.metadata("test.json")?.len();
+            let stats = db.commit_stats()?;
+
+            // The whole array is rewritten, but only the growth is reported.
+            let len_after = dd_rc.metadata("test.json")?.len();
+            assert_eq!(stats.bytes, len_after - len_before);
+
+            let array: Vec<serde_json::Value> =
+                serde_json::from_str(&dd_rc.read_to_string("test.json")?)?;
+            assert_eq!(array.len(), fixture.len());
+
+            db.insert(None, fixture[0].clone())?;
+            let stats = db.commit_stats()?;
+            assert_eq!(stats.docs, 1);
+            assert_eq!(stats.bytes, dd_rc.metadata("test.json")?.len() - len_after);
+            assert!(stats.bytes < len_after);
+
+            db.delete(keys[0].clone())?;
+            db.commit()?;
+            keys[0].id()
+        };
+
+        let array: Vec<serde_json::Value> =
+            serde_json::from_str(&dd_rc.read_to_string("test.json")?)?;
+        assert_eq!(array.len(), fixture.len() + 1);
+
+        let db = open()?;
+        assert_eq!(db.len(), fixture.len());
+        assert_eq!(db.get(&deleted), None);
+        assert!(db.get_raw(&deleted).is_some_and(|doc| doc.has_flag(&Flag::Deleted)));
+        let mut found = db.find(&FnQuery(|_msg: &TestMessage| true));
+        found.sort_by_key(|msg| format!("{:?}", msg));
+        let mut expected = fixture.to_vec();
+        expected.sort_by_key(|msg| format!("{:?}", msg));
+        assert_eq!(found, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn doc_with_flags() -> Result<()> {
+        let key = VersionedKey::new(IndexKey::Num(1));
+        let doc = Doc::<TestMessage>::with_flags(key.clone(), None, HashSet::from([Flag::Binary]));
+        assert!(doc.has_flag(&Flag::Binary));
+        assert!(!doc.has_flag(&Flag::Deleted));
+
+        let json = serde_json::to_string(&doc)?;
+        let decoded: Doc<TestMessage> = serde_json::from_str(&json)?;
+        assert_eq!(decoded, doc);
+        assert_eq!(decoded.flags(), &HashSet::from([Flag::Binary]));
+
+        assert_eq!(
+            Doc::new(key.clone(), None),
+            Doc::<TestMessage>::with_flags(key, None, HashSet::new())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn retained_omitted_when_empty() -> Result<()> {
+        let fixture = msg_fixture();
+        let empty = Doc::<TestMessage>::new(VersionedKey::new(IndexKey::Num(1)), None);
+        let mut retained = empty.clone();
+        retained.retained = Some(fixture[0].clone());
+
+        let json = serde_json::to_value(&empty)?;
+        assert!(json.get("retained").is_none());
+        assert_eq!(serde_json::from_value::<Doc<TestMessage>>(json)?, empty);
+
+        let json = serde_json::to_value(&retained)?;
+        assert!(json.get("retained").is_some());
+        assert_eq!(serde_json::from_value::<Doc<TestMessage>>(json)?, retained);
+
+        // Binary formats always carry the field.
+        #[cfg(feature = "bincode")]
+        for doc in [&empty, &retained] {
+            let bytes = bincode::serialize(doc)?;
+            assert_eq!(&bincode::deserialize::<Doc<TestMessage>>(&bytes)?, doc);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn get_hides_tombstones() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (key1, msg1) = msgs.first().unwrap();
+        assert_eq!(db.get(&key1.id()), db.get_raw(&key1.id()));
+
+        db.delete(key1.clone())?;
+        assert!(db.get(&key1.id()).is_none());
+
+        let tombstone = db.get_raw(&key1.id()).unwrap();
+        assert!(tombstone.has_flag(&Flag::Deleted));
+        assert!(tombstone.obj().is_none());
+
+        // Writing the id again revives it.
+        db.insert(Some(VersionedKey::new(key1.id())), msg1.clone())?;
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn purge_tombstones_before() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, msg2) = msgs.get(1).unwrap();
+        db.delete(key1.clone())?;
+        db.commit()?;
+
+        assert_eq!(db.purge_tombstones_before(UNIX_EPOCH)?, 0);
+        assert!(db.get_raw(&key1.id()).is_some());
+
+        let later = SystemTime::now() + Duration::from_secs(1);
+        assert_eq!(db.purge_tombstones_before(later)?, 1);
+        assert_eq!(db.get_raw(&key1.id()), None);
+        assert!(db.history(&key1.id()).is_empty());
+        assert_eq!(db.deleted_ids(), vec![]);
+        assert_eq!(db.len(), msgs.len() - 1);
+
+        // A commit after the purge mustn't leave the file holding the ids.
+        let key3 = db.insert(None, msg2.clone())?;
+        db.commit()?;
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.get_raw(&key1.id()), None);
+        assert!(db.history(&key1.id()).is_empty());
+        assert_eq!(db.get(&key2.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+        assert_eq!(db.get(&key3.id()).and_then(|doc| doc.obj), Some(msg2.clone()));
+        assert_eq!(db.count(), msgs.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn summary() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let fixture = msg_fixture();
+
+        assert_eq!(db.summary(), Summary {
+            live: 3,
+            deleted: 0,
+            binary: 0,
+            stale_versions: 0,
+            distinct_ids: 3,
+        });
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        let key1 = db.insert(Some(key1.clone()), fixture[1].clone())?;
+        db.insert(Some(key1), fixture[2].clone())?;
+        db.delete(key2.clone())?;
+        db.insert(None, fixture[0].clone())?;
+        db.commit()?;
+
+        // Only the newest version of each id is kept in memory after a write.
+        let summary = db.summary();
+        assert_eq!(summary, Summary {
+            live: 3,
+            deleted: 1,
+            binary: 0,
+            stale_versions: 0,
+            distinct_ids: 4,
+        });
+        assert_eq!(summary.live, db.len());
+        assert_eq!(summary.deleted, db.deleted_ids().len());
+
+        // Reading the file back, before drop compacts it, finds every
+        // version written.
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc, "test.ndjson")?;
+        let summary = reader.summary();
+        assert_eq!(summary, Summary {
+            live: 3,
+            deleted: 1,
+            binary: 0,
+            stale_versions: 3,
+            distinct_ids: 4,
+        });
+        assert_eq!(summary.distinct_ids + summary.stale_versions, reader.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_ids() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        assert!(db.deleted_ids().is_empty());
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key3, _msg3) = msgs.get(2).unwrap();
+        db.delete(key1.clone())?;
+        db.delete(key3.clone())?;
+
+        let mut expected = vec![key1.id(), key3.id()];
+        expected.sort();
+        assert_eq!(db.deleted_ids(), expected);
+
+        // A revived id is no longer listed.
+        db.insert(Some(key1.incr()), msgs[0].1.clone())?;
+        assert_eq!(db.deleted_ids(), vec![key3.id()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn undelete() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let open = || {
+            Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+                .retain_deleted(true)
+                .open()
+        };
+
+        let (key1, key2) = {
+            let mut db = open()?;
+            let key1 = db.insert(None, fixture[0].clone())?;
+            let key2 = db.insert(None, fixture[1].clone())?;
+            db.delete(key1.clone())?;
+            db.delete(key2.clone())?;
+            db.compact()?;
+            (key1, key2)
+        };
+
+        // The retained object survives a compaction and reopen.
+        let mut db = open()?;
+        assert_eq!(db.get(&key1.id()), None);
+        assert!(db.get_raw(&key1.id()).is_some_and(|doc| doc.obj().is_none()));
+
+        assert_eq!(db.undelete(&key1.id())?, Some(fixture[0].clone()));
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        assert_eq!(db.undelete(&key1.id())?, None);
+        assert_eq!(db.undelete(&IndexKey::Num(-1))?, None);
+        drop(db);
+
+        let mut db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+
+        // Without `retain_deleted`, tombstones forget their object.
+        let key3 = db.insert(None, fixture[2].clone())?;
+        db.delete(key3.clone())?;
+        assert_eq!(db.undelete(&key3.id())?, None);
+        assert_eq!(db.undelete(&key2.id())?, Some(fixture[1].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_reviving() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        let fixture = msg_fixture();
+
+        let (key1, msg1) = msgs.first().unwrap();
+        let tombstone = key1.incr();
+        db.delete(key1.clone())?;
+
+        let (key, revived) = db.insert_reviving(Some(tombstone), msg1.clone())?;
+        assert!(revived);
+        assert_eq!(db.get(&key.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+
+        let (_key, revived) = db.insert_reviving(Some(key), fixture[1].clone())?;
+        assert!(!revived);
+
+        let (_key, revived) = db.insert_reviving(None, fixture[0].clone())?;
+        assert!(!revived);
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_num_id() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, None, true)?;
+        let fixture = msg_fixture();
+        assert_eq!(db.max_num_id(), None);
+
+        for (num, msg) in [3, 7, 5].into_iter().zip(&fixture) {
+            db.insert(Some(VersionedKey::new(IndexKey::Num(num))), msg.clone())?;
+        }
+        db.insert(Some(VersionedKey::new(IndexKey::Float(OrderedFloat(9.5)))), fixture[0].clone())?;
+        assert_eq!(db.max_num_id(), Some(7));
+
+        let key = db.insert(Some(VersionedKey::new(IndexKey::Num(7))), fixture[1].clone())?;
+        assert_eq!(db.max_num_id(), Some(7));
+
+        db.delete(key)?;
+        assert_eq!(db.max_num_id(), Some(5));
+
+        Ok(())
+    }
+
+    #[test]
+    fn contains_ignore_case() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (_key1, msg1) = msgs.first().unwrap();
+        assert_eq!(db.find(&ContainsIgnoreCase::new("HELLO", val_field)), vec![msg1.clone()]);
+        assert_eq!(db.find(&ContainsIgnoreCase::new("o", val_field)).len(), 2);
+        assert!(db.find(&ContainsIgnoreCase::new("kind", val_field)).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn range_query() -> Result<()> {
+        fn kind(msg: &TestMessage) -> u16 {
+            match msg {
+                TestMessage::Of { kind, val: _ } | TestMessage::Empty { kind } => *kind,
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        let two = TestMessage::Of { kind: 2, val: "hello two".to_string() };
+        db.insert(None, two.clone())?;
+        db.insert(None, TestMessage::Of { kind: 3, val: "hello three".to_string() })?;
+
+        assert_eq!(db.find(&RangeQuery::new(1..=2, kind)).len(), 3);
+        assert_eq!(db.find(&RangeQuery::new(..1, kind)).len(), 1);
+        assert_eq!(db.find(&RangeQuery::new(3.., kind)).len(), 1);
+
+        // combined with other filters
+        let in_range: QueryRef<'_, TestMessage> = &RangeQuery::new(1..=2, kind);
+        let hello: QueryRef<'_, TestMessage> = &val_filter("hello");
+        let both = in_range & hello;
+        let found = db.find(&both);
+        assert_eq!(found.len(), 2);
+        assert!(found.contains(&msgs[0].1) && found.contains(&two));
+
+        let only_two: QueryRef<'_, TestMessage> = &RangeQuery::new(2..=2, kind);
+        let both = only_two & hello;
+        assert_eq!(db.find(&both), vec![two]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn in_query() -> Result<()> {
+        fn kind(msg: &TestMessage) -> u16 {
+            match msg {
+                TestMessage::Of { kind, val: _ } | TestMessage::Empty { kind } => *kind,
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        db.insert(None, TestMessage::Of { kind: 2, val: "hello two".to_string() })?;
+
+        let found = db.find(&InQuery::new([0, 1], kind));
+        assert_eq!(found.len(), msgs.len());
+        assert!(msgs.iter().all(|(_key, msg)| found.contains(msg)));
+
+        assert_eq!(db.find(&InQuery::new([2, 5], kind)).len(), 1);
+        assert!(db.find(&InQuery::new(HashSet::new(), kind)).is_empty());
+
+        // combined with other filters
+        let in_set: QueryRef<'_, TestMessage> = &InQuery::new([0, 1], kind);
+        let hello: QueryRef<'_, TestMessage> = &val_filter("hello");
+        let both = in_set & hello;
+        assert_eq!(db.find(&both), vec![msgs[0].1.clone()]);
+
+        let outside = !in_set;
+        assert_eq!(db.find(&outside).len(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derive_indexer() -> Result<()> {
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Indexer)]
+        struct Ticket {
+            #[index]
+            title: String,
+            #[index]
+            priority: u8,
+            #[index]
+            open: bool,
+            #[index(as = "str")]
+            number: u32,
+            note: String,
+        }
+
+        impl DocType for Ticket {}
+
+        #[derive(Debug)]
+        struct HandPriorityIndexer;
+
+        impl Indexer<Ticket> for HandPriorityIndexer {
+            fn index(&self, ticket: &Ticket) -> Vec<IndexKey> {
+                vec![IndexKey::Num(ticket.priority as i64)]
+            }
+        }
+
+        let ticket = Ticket {
+            title: "broken".to_string(),
+            priority: 2,
+            open: true,
+            number: 42,
+            note: "not indexed".to_string(),
+        };
+
+        assert_eq!(TicketPriorityIndexer.index(&ticket), HandPriorityIndexer.index(&ticket));
+        assert_eq!(TicketTitleIndexer.index(&ticket), vec![IndexKey::Str(KString::from_static("broken"))]);
+        assert_eq!(TicketOpenIndexer.index(&ticket), vec![IndexKey::Num(1)]);
+        assert_eq!(TicketNumberIndexer.index(&ticket), vec![IndexKey::Str(KString::from_static("42"))]);
+
+        let mut db = Mudb::<Ticket>::in_memory();
+        db.add_view(&KString::from_static("priority"), Box::new(TicketPriorityIndexer))?;
+        db.insert(None, ticket.clone())?;
+        db.insert(None, Ticket { priority: 1, ..ticket.clone() })?;
+        db.build_views()?;
+        assert_eq!(db.find_by_view("priority", IndexKey::Num(2)), vec![ticket]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derive_queryable() -> Result<()> {
+        #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Queryable)]
+        struct Ticket {
+            title: String,
+            priority: u8,
+            open: bool,
+            tags: Vec<String>,
+        }
+
+        impl DocType for Ticket {}
+
+        let ticket = |title: &str, priority, open| Ticket {
+            title: title.to_string(),
+            priority,
+            open,
+            tags: vec![],
+        };
+
+        let mut db = Mudb::<Ticket>::in_memory();
+        db.insert(None, ticket("disk full", 1, true))?;
+        db.insert(None, ticket("disk slow", 2, true))?;
+        db.insert(None, ticket("typo", 1, false))?;
+
+        assert_eq!(db.find(&Ticket::query().title_eq("typo")), vec![ticket("typo", 1, false)]);
+        assert_eq!(db.count_match(&Ticket::query().priority_eq(1)), 2);
+        assert_eq!(db.count_match(&Ticket::query().open_eq(true)), 2);
+        assert_eq!(db.count_match(&Ticket::query().title_contains("disk")), 2);
+        assert_eq!(db.count_match(&Ticket::query().title_contains("Disk")), 0);
+
+        let (open, urgent) = (Ticket::query().open_eq(true), Ticket::query().priority_eq(1));
+        let both = QueryOp::And(&open, &urgent);
+        assert_eq!(db.find(&both), vec![ticket("disk full", 1, true)]);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex_query() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc, None, true)?;
+
+        let (_key2, msg2) = msgs.get(1).unwrap();
+        let query = RegexQuery::new(r"^good\w+ my", val_field)?;
+        assert_eq!(db.find(&query), vec![msg2.clone()]);
+
+        let either = RegexQuery::new("(?i)EVERYONE|FRIENDS$", val_field)?;
+        assert_eq!(db.find(&either).len(), 2);
+
+        assert!(matches!(RegexQuery::new("(", val_field), Err(MudbError::Invalid(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracing_fields() -> Result<()> {
+        use std::sync::Mutex;
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        type Fields = Arc<Mutex<Vec<(String, String)>>>;
+
+        struct Capture(Fields);
+
+        impl Visit for Capture {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.lock().unwrap().push((field.name().to_string(), format!("{:?}", value)));
+            }
+        }
+
+        struct CaptureLayer(Fields);
+
+        impl <S: tracing::Subscriber> Layer<S> for CaptureLayer {
+            fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+                attrs.record(&mut Capture(self.0.clone()));
+            }
+
+            fn on_record(&self, _id: &Id, values: &Record<'_>, _ctx: Context<'_, S>) {
+                values.record(&mut Capture(self.0.clone()));
+            }
+        }
+
+        let fields = Fields::default();
+        let subscriber = tracing_subscriber::registry().with(CaptureLayer(fields.clone()));
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+
+        tracing::subscriber::with_default(subscriber, || -> Result<()> {
+            let (mut db, _msgs) = init_db(dd_rc, None, true)?;
+            db.commit()?;
+            db.find(&val_filter("hello"));
+            db.find_by_view("kind", IndexKey::Num(1));
+            db.find_by_view("missing", IndexKey::Num(1));
+            Ok(())
+        })?;
+
+        let fields = fields.lock().unwrap();
+        let has = |name: &str, value: &str| fields.contains(&(name.to_string(), value.to_string()));
+        assert!(has("docs", "3"));
+        assert!(fields.iter().any(|(name, _)| name == "bytes"));
+        assert!(has("scanned", "3"));
+        assert!(has("matched", "1"));
+        assert!(has("hit", "true"));
+        assert!(has("ids", "2"));
+        assert!(has("hit", "false"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn write_buffer_capacity() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::builder(dd_rc.clone(), "test.ndjson")
+            .write_buffer_capacity(64 * 1024)
+            .durability(Durability::None)
+            .open()?;
+        let capacity = |db: &Mudb<TestMessage>| db.backing.as_ref().unwrap().writer.capacity();
+        assert_eq!(capacity(&db), 64 * 1024);
+
+        for msg in &fixture {
+            db.insert(None, msg.clone())?;
+            db.commit()?;
+        }
+        assert!(!db.backing.as_ref().unwrap().writer.buffer().is_empty());
+
+        db.compact()?;
+        assert_eq!(capacity(&db), 64 * 1024);
+        drop(db);
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.len(), fixture.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_into_matches_encode() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        db.insert_binary(IndexKey::Num(1), vec![0, 159, 146, 150])?;
+        for msg in &fixture {
+            db.insert(None, msg.clone())?;
+        }
+
+        let expected: Vec<u8> = db.changed.iter()
+            .map(|doc| JsonLines.encode(doc))
+            .collect::<Result<Vec<_>, _>>()?
+            .concat();
+        db.commit()?;
+
+        let mut written = vec![];
+        dd_rc.open("test.ndjson")?.read_to_end(&mut written)?;
+        assert_eq!(written, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn find_by_views() -> Result<()> {
+        #[derive(Debug)]
+        struct FirstWordIndexer;
+
+        impl Indexer<TestMessage> for FirstWordIndexer {
+            fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+                match msg {
+                    TestMessage::Of { kind: _, val } => val.split(' ')
+                        .take(1)
+                        .map(|word| IndexKey::Str(KString::from_ref(word)))
+                        .collect(),
+                    _ => vec![],
+                }
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        db.insert(None, TestMessage::Of {
+            kind: 2,
+            val: "hello again".to_string(),
+        })?;
+        db.add_view(&KString::from_static("word"), Box::new(FirstWordIndexer))?;
+        db.build_views()?;
+
+        let hello = IndexKey::Str(KString::from_static("hello"));
+        assert_eq!(db.find_by_view("word", hello.clone()).len(), 2);
+
+        let (_key1, msg1) = msgs.first().unwrap();
+        let found = db.find_by_views(&[("kind", IndexKey::Num(1)), ("word", hello.clone())]);
+        assert_eq!(found, vec![msg1.clone()]);
+
+        assert!(db.find_by_views(&[("kind", IndexKey::Num(0)), ("word", hello.clone())]).is_empty());
+        assert!(db.find_by_views(&[("kind", IndexKey::Num(1)), ("nonesuch", hello)]).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn view_counts() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        db.insert(None, TestMessage::Of {
+            kind: 2,
+            val: "another kind".to_string(),
+        })?;
+        db.build_views()?;
+
+        let counts = db.view_counts("kind");
+        assert_eq!(counts, BTreeMap::from([(IndexKey::Num(1), 2), (IndexKey::Num(2), 1)]));
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        db.delete(key1.clone())?;
+        db.delete(key2.clone())?;
+        db.build_views()?;
+
+        assert_eq!(db.view_counts("kind"), BTreeMap::from([(IndexKey::Num(2), 1)]));
+        assert!(db.view_counts("nonesuch").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn group_by() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+
+        for val in ["two", "more"] {
+            db.insert(None, TestMessage::Of {
+                kind: 2,
+                val: val.to_string(),
+            })?;
+        }
+        db.build_views()?;
+
+        let val_len = |sum, msg: &TestMessage| sum + val_field(msg).map_or(0, str::len);
+        let expected = "hello everyone".len() + "goodbye my friends".len();
+        assert_eq!(
+            db.group_by("kind", 0, val_len),
+            BTreeMap::from([(IndexKey::Num(1), expected), (IndexKey::Num(2), 7)])
+        );
+
+        let (key1, _msg1) = msgs.first().unwrap();
+        let (key2, _msg2) = msgs.get(1).unwrap();
+        db.delete(key1.clone())?;
+        db.delete(key2.clone())?;
+        db.build_views()?;
+
+        assert_eq!(
+            db.group_by("kind", 0, |count, _msg| count + 1),
+            BTreeMap::from([(IndexKey::Num(2), 2)])
+        );
+        assert!(db.group_by("nonesuch", 0, val_len).is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn multi_valued_view() -> Result<()> {
+        #[derive(Debug)]
+        struct TagIndexer;
+
+        impl Indexer<TestMessage> for TagIndexer {
+            fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+                match msg {
+                    TestMessage::Of { kind: _, val } => val.split(' ')
+                        .map(|tag| IndexKey::Str(KString::from(format!("tag:{}", tag))))
+                        .collect(),
+                    _ => vec![],
+                }
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, Some(vec![]), true)?;
+        db.add_view(&KString::from_static("tags"), Box::new(TagIndexer))?;
+
+        let key = db.insert(None, TestMessage::Of {
+            kind: 1,
+            val: "a b a".to_string(),
+        })?;
+        db.build_views()?;
+
+        let tag = |name: &'static str| IndexKey::Str(KString::from_static(name));
+        assert_eq!(db.find_by_view("tags", tag("tag:a")).len(), 1);
+        assert_eq!(db.find_by_view("tags", tag("tag:b")).len(), 1);
+        assert_eq!(
+            db.view_counts("tags"),
+            BTreeMap::from([(tag("tag:a"), 1), (tag("tag:b"), 1)])
+        );
+
+        db.insert(Some(key), TestMessage::Of {
+            kind: 1,
+            val: "b".to_string(),
+        })?;
+        db.build_views()?;
+        assert_eq!(db.view_counts("tags"), BTreeMap::from([(tag("tag:b"), 1)]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn view_range() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, Some(vec![]), true)?;
+
+        for kind in 1..=8 {
+            db.insert(None, TestMessage::Of {
+                kind,
+                val: format!("kind {}", kind),
+            })?;
+        }
+        db.build_views()?;
+
+        let mut kinds: Vec<u16> = db.find_by_view_range(
+            "kind",
+            IndexKey::Num(1)..=IndexKey::Num(5)
+        ).iter().map(|msg| match msg {
+            TestMessage::Of { kind, val: _ } => *kind,
+            TestMessage::Empty { kind } => *kind,
+        }).collect();
+        kinds.sort();
+
+        assert_eq!(kinds, vec![1, 2, 3, 4, 5]);
+        assert_eq!(db.find_by_view_range("kind", IndexKey::Num(9)..).len(), 0);
+        assert_eq!(db.find_by_view_range("nonesuch", ..).len(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tuple_keys() -> Result<()> {
+        #[derive(Debug)]
+        struct KindValIndexer;
+
+        impl Indexer<TestMessage> for KindValIndexer {
+            fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+                match msg {
+                    TestMessage::Of { kind, val } => vec![IndexKey::Tuple(vec![
+                        IndexKey::Num(*kind as i64),
+                        IndexKey::Str(KString::from_ref(val)),
+                    ])],
+                    _ => vec![],
+                }
+            }
+        }
+
+        let mut db = Mudb::<TestMessage>::in_memory();
+        db.add_view(&KString::from_static("kind_val"), Box::new(KindValIndexer))?;
+
+        for kind in 1..=3 {
+            for val in ["a", "b", "c"] {
+                db.insert(None, TestMessage::Of { kind, val: format!("{}{}", val, kind) })?;
+            }
+        }
+        db.build_views()?;
+
+        let partition = |kind| IndexKey::Tuple(vec![IndexKey::Num(kind)]);
+        let mut vals: Vec<String> = db.find_by_view_range("kind_val", partition(2)..partition(3))
+            .iter()
+            .map(|msg| msg.val())
+            .collect();
+        vals.sort();
+        assert_eq!(vals, vec!["updated: a2", "updated: b2", "updated: c2"]);
+
+        // A tuple key as a doc id, and its untagged form.
+        let id = IndexKey::Tuple(vec![IndexKey::Num(1), IndexKey::Str(KString::from_static("a"))]);
+        let json = serde_json::to_string(&id)?;
+        assert_eq!(json, r#"[1,"a"]"#);
+        assert_eq!(serde_json::from_str::<IndexKey>(&json)?, id);
+        assert_eq!(serde_json::from_str::<IndexKey>("1")?, IndexKey::Num(1));
+
+        let key = db.insert(Some(VersionedKey::new(id.clone())), msg_fixture()[0].clone())?;
+        let doc = db.get(&id).unwrap();
+        assert_eq!(serde_json::from_str::<Doc<TestMessage>>(&serde_json::to_string(&doc)?)?, doc);
+        assert_eq!(doc.key(), &key);
+
+        Ok(())
+    }
+
+    #[test]
+    fn float_keys() -> Result<()> {
+        #[derive(Debug)]
+        struct HalfKindIndexer;
+
+        impl Indexer<TestMessage> for HalfKindIndexer {
+            fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
+                match msg {
+                    TestMessage::Of { kind, val: _ } =>
+                        vec![IndexKey::Float(OrderedFloat(*kind as f64 / 2.0))],
+                    _ => vec![],
+                }
+            }
+        }
+
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let float_id = IndexKey::Float(OrderedFloat(2.0));
+        let num_id = IndexKey::Num(2);
+
+        {
+            let (mut db, _msgs) = init_db(dd_rc.clone(), None, true)?;
+            db.add_view(&KString::from_static("half"), Box::new(HalfKindIndexer))?;
+            db.build_views()?;
+
+            assert_eq!(db.find_by_view("half", IndexKey::Float(OrderedFloat(0.5))).len(), 2);
+            assert_eq!(db.find_by_view("half", IndexKey::Float(OrderedFloat(1.0))).len(), 0);
+            assert_eq!(db.find_by_view("half", IndexKey::Num(0)).len(), 0);
+
+            db.insert(Some(VersionedKey::new(float_id.clone())), fixture[0].clone())?;
+            db.insert(Some(VersionedKey::new(num_id.clone())), fixture[1].clone())?;
+        }
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.get(&float_id).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        assert_eq!(db.get(&num_id).and_then(|doc| doc.obj), Some(fixture[1].clone()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn non_finite_float_keys() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+
+        let ids: Vec<IndexKey> = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY]
+            .into_iter()
+            .map(|f| IndexKey::Float(OrderedFloat(f)))
+            .collect();
+
+        for id in &ids {
+            let json = serde_json::to_string(id)?;
+            assert_eq!(&serde_json::from_str::<IndexKey>(&json)?, id);
+        }
+        assert_eq!(serde_json::to_string(&ids[0])?, r#"{"Float":"NaN"}"#);
+
+        {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            for id in &ids {
+                db.insert(Some(VersionedKey::new(id.clone())), fixture[0].clone())?;
+            }
+            db.commit()?;
+        }
+
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        for id in &ids {
+            assert_eq!(db.get(id).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        }
+
+        Ok(())
     }
 
-    fn init_db(
-        dd_rc: Rc<Dir>,
-        msgs: Option<Vec<TestMessage>>,
-        add_fixtures: bool,
-    ) -> Result<(
-        Mudb<TestMessage>,
-        Vec<(VersionedKey, TestMessage)>
-    )> {
+    #[test]
+    fn fork() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let fixture = msg_fixture();
+        let (key1, msg1) = msgs.first().unwrap();
 
-        let msgs = msgs.unwrap_or_else(|| msg_fixture());
+        {
+            let mut fork = db.fork(&dd_rc, "fork.ndjson")?;
+            assert_eq!(fork.len(), msgs.len());
+
+            fork.delete(key1.clone())?;
+            fork.insert(None, fixture[0].clone())?;
+            fork.commit()?;
+            assert_eq!(fork.len(), msgs.len());
+            assert_eq!(fork.get(&key1.id()), None);
+        }
 
-        let mut mudb = Mudb::<TestMessage>::open(
-            dd_rc.clone(),
-            "test.ndjson",
-        )?;
+        assert_eq!(db.len(), msgs.len());
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+        drop(db);
 
-        let results = if add_fixtures {
-            let view = View::<TestMessage>::new(
-                Box::new(MsgKindIndexer{})
-            );
+        let db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        assert_eq!(db.len(), msgs.len());
+        assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
 
-            mudb.views.insert(KString::from_static("kind"), RefCell::new(view));
+        let fork = Mudb::<TestMessage>::open(dd_rc, "fork.ndjson")?;
+        assert_eq!(fork.get(&key1.id()), None);
 
-            let results = msgs.iter().map(|msg| {
-                let key = mudb.insert(None, msg.clone()).unwrap();
-                (key, msg.clone())
-            }).collect();
+        Ok(())
+    }
 
-            mudb.build_views()?;
-            mudb.commit()?;
-            mudb.compact()?;
+    #[test]
+    fn snapshot() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, None, true)?;
+        let fixture = msg_fixture();
 
-            results
-        } else {
-            vec![]
-        };
+        let snap = db.snapshot();
+        assert_eq!(snap.len(), msgs.len());
 
-        Ok((mudb, results))
-    }
+        let (key1, msg1) = msgs.first().unwrap();
+        db.insert(None, fixture[0].clone())?;
+        db.delete(key1.clone())?;
+        db.commit()?;
 
-    #[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug, Hash)]
-    enum TestMessage {
-        Empty { kind: u16, },
-        Of { kind: u16, val: String },
+        assert_eq!(db.len(), msgs.len());
+        assert_eq!(snap.len(), msgs.len());
+        assert_eq!(snap.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+        assert_eq!(db.get(&key1.id()), None);
+        assert_eq!(snap.find(&FnQuery(|msg: &TestMessage| msg == msg1)), vec![msg1.clone()]);
+        assert_eq!(snap.iter().count(), msgs.len());
+
+        Ok(())
     }
 
-    impl DocType for TestMessage {}
+    #[test]
+    fn open_in_subdirectory() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
+        let filename = "shard/01/test.ndjson";
 
-    impl TestMessage {
-        fn val(&self) -> String {
-            match self {
-                TestMessage::Of { val, kind: _ } => format!("updated: {}", val),
-                TestMessage::Empty { kind: _ } => "new message".to_string(),
-            }
-        }
-    }
+        let key = {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), filename)?;
+            let key = db.insert(None, fixture[0].clone())?;
+            db.compact()?;
+            key
+        };
 
-    #[derive(Debug, Clone)]
-    struct MessageValQuery {
-        val: String,
-    }
+        assert!(dd_rc.metadata(filename)?.is_file());
 
-    impl <'a> Query<'a, TestMessage> for MessageValQuery {
-        fn matches(&self, obj: &'a TestMessage) -> bool {
-            match obj {
-                TestMessage::Empty { kind: _ } => false,
-                TestMessage::Of { kind: _, val } =>
-                    (*val).contains(&self.val),
-            }
-        }
+        let db = Mudb::<TestMessage>::open(dd_rc.clone(), filename)?;
+        assert_eq!(db.get(&key.id).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+
+        assert!(Mudb::<TestMessage>::open(dd_rc, "../escape/test.ndjson").is_err());
+
+        Ok(())
     }
 
-    fn val_filter(val: &str) -> MessageValQuery {
-        MessageValQuery {
-            val: val.to_string(),
-        }
+    #[test]
+    fn close() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let fixture = msg_fixture();
+
+        let key = db.insert(None, fixture[0].clone())?;
+        db.close()?;
+
+        // The lock is gone, and the uncommitted insert made it to disk.
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.get(&key.id()).and_then(|doc| doc.obj), Some(fixture[0].clone()));
+        assert_eq!(db.len(), msgs.len() + 1);
+
+        Ok(())
     }
 
-    #[derive(Debug, Clone)]
-    struct MsgKindIndexer {}
+    #[test]
+    fn close_reports_errors() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc.clone(), None, true)?;
+        let fixture = msg_fixture();
 
-    impl Indexer<TestMessage> for MsgKindIndexer {
-        fn index(&self, msg: &TestMessage) -> Vec<IndexKey> {
-            match msg {
-                TestMessage::Of { kind, val: _ } =>
-                    vec![IndexKey::Num(*kind as i64)],
-                _ => vec![],
-            }
+        // Swap in a writer over a read-only handle, which fails to write.
+        if let Some(backing) = &mut db.backing {
+            backing.writer = BufWriter::new(dd_rc.open("test.ndjson")?);
         }
+
+        db.insert(None, fixture[0].clone())?;
+        assert!(matches!(db.close(), Err(MudbError::Io(_))));
+
+        Ok(())
     }
 
     #[test]
-    fn basic_durability() -> Result<()> {
+    fn read_only() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
+        let dd_rc = Arc::new(data_dir);
+        let (db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        drop(db);
 
-        let fixture = msg_fixture();
-        let key1 = {
-            let (db, msgs) = init_db(
-                dd_rc.clone(),
-                Some(fixture.clone()),
-                true
-            )?;
+        let before = dd_rc.read("test.ndjson")?;
+        let (key1, msg1) = msgs.first().unwrap();
 
-            let (key1, msg1) = msgs.get(0).unwrap();
-            let (key2, msg2) = msgs.get(1).unwrap();
+        {
+            let mut db = Mudb::<TestMessage>::open_read_only(dd_rc.clone(), "test.ndjson")?;
+            assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+
+            assert!(matches!(db.insert(None, msg1.clone()), Err(MudbError::ReadOnly)));
+            assert!(matches!(db.delete(key1.clone()), Err(MudbError::ReadOnly)));
+            assert!(matches!(
+                db.update(key1, Box::new(|msg: &TestMessage| msg.clone())),
+                Some(Err(MudbError::ReadOnly))
+            ));
+            assert!(matches!(db.commit(), Err(MudbError::ReadOnly)));
+            assert!(matches!(db.compact(), Err(MudbError::ReadOnly)));
+            assert!(!db.modified());
+        }
 
-            assert_eq!(
-                db.get(&key1.id()).map(|doc| doc.obj).flatten(),
-                Some(msg1.clone())
-            );
+        assert_eq!(dd_rc.read("test.ndjson")?, before);
+        assert!(Mudb::<TestMessage>::open_read_only(dd_rc, "missing.ndjson").is_err());
 
-            assert_eq!(
-                db.get(&key2.id()).map(|doc| doc.obj).flatten(),
-                Some(msg2.clone())
-            );
+        Ok(())
+    }
 
-            key1.clone()
-        };
+    #[test]
+    fn write_lock() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let (db, _msgs) = init_db(dd_rc.clone(), None, true)?;
 
-        {
-            let (mut db, _msgs) = init_db(dd_rc.clone(), Some(vec![]), true)?;
-            let msg1 = fixture.get(0).unwrap();
-            let msg2 = fixture.get(1).unwrap();
+        match Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson") {
+            Err(MudbError::Locked(name)) => assert_eq!(name, "test.ndjson.lock"),
+            other => panic!("expected a lock error, got {:?}", other),
+        }
 
-            assert_eq!(
-                db.get(&key1.id()).map(|doc| doc.obj).flatten(),
-                Some(msg1.clone())
-            );
+        let reader = Mudb::<TestMessage>::open_read_only(dd_rc.clone(), "test.ndjson")?;
+        assert_eq!(reader.count(), db.count());
 
-            let key3 = db.insert(Some(key1.clone()), msg2.clone())?;
+        drop(db);
+        assert!(!dd_rc.exists("test.ndjson.lock"));
 
-            assert_eq!(key3.id(), key1.id());
-            assert!(key3 != key1);
-            assert_eq!(
-                db.get(&key1.id()).map(|doc| doc.obj).flatten(),
-                Some(msg2.clone())
-            );
+        let db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        assert_eq!(db.count(), reader.count());
 
-            assert_eq!(db.count(), fixture.len());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale_write_lock() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        init_db(dd_rc.clone(), None, true)?;
+
+        // A lock naming a process that has exited is taken over...
+        let mut child = std::process::Command::new("true").spawn()?;
+        let dead = child.id();
+        child.wait()?;
+        dd_rc.write("test.ndjson.lock", format!("{}\n", dead))?;
+
+        let db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+        assert_eq!(db.count(), 3);
+        drop(db);
+        assert!(!dd_rc.exists("test.ndjson.lock"));
+
+        // ...but one naming a live process, or no pid at all, is not.
+        for holder in [format!("{}\n", std::process::id()), String::new()] {
+            dd_rc.write("test.ndjson.lock", holder)?;
+            assert!(matches!(
+                Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson"),
+                Err(MudbError::Locked(_))
+            ));
         }
 
         Ok(())
     }
 
     #[test]
-    fn versioning() -> Result<()> {
+    fn remove_view() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
-        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, _msgs) = init_db(dd_rc, None, true)?;
 
-        let (key1, msg1) = msgs.get(0).unwrap();
-        let init = db.get(&key1.id).unwrap().obj.unwrap();
-        assert_eq!(init, msg1.clone());
+        db.add_view(&KString::from_static("also_kind"), Box::new(MsgKindIndexer {}))?;
+        db.build_views()?;
+        assert_eq!(db.view_names(), vec!["also_kind", "kind"]);
 
-        let key2 = db.update(
-            key1,
-            Box::new(|msg: &TestMessage| msg.clone())
-        ).unwrap()?;
-        assert_eq!(key2.id, key1.id);
-        assert!(key2.ver > key1.ver);
-        assert_eq!(key1.incr(), key2);
+        assert!(db.remove_view("kind"));
+        assert!(!db.remove_view("kind"));
+        assert_eq!(db.view_names(), vec!["also_kind"]);
+
+        assert!(db.find_by_view("kind", IndexKey::Num(1)).is_empty());
+        assert_eq!(db.find_by_view("also_kind", IndexKey::Num(1)).len(), 2);
 
         Ok(())
     }
 
     #[test]
-    fn compact() -> Result<()> {
+    fn unique_view() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
-        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
+        let dd_rc = Arc::new(data_dir);
+        let (mut db, msgs) = init_db(dd_rc, Some(vec![]), false)?;
+        let fixture = msg_fixture();
 
-        let _ = db.compact()?;
-        let (key1, msg1) = msgs.get(0).unwrap();
+        let name = KString::from_static("kind");
+        db.add_unique_view(&name, Box::new(MsgKindIndexer {}))?;
 
-        assert_eq!(db.count(), msgs.len());
-        assert_eq!(
-            db.get(&key1.id()).map(|doc| doc.obj).flatten(),
-            Some(msg1.clone())
-        );
+        let key1 = db.insert(None, fixture[0].clone())?;
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)), vec![fixture[0].clone()]);
+
+        match db.insert(None, fixture[1].clone()) {
+            Err(MudbError::UniqueViolation { view, key }) => {
+                assert_eq!(view, name);
+                assert_eq!(key, IndexKey::Num(1));
+            },
+            other => panic!("expected a unique violation, got {:?}", other),
+        }
+        assert_eq!(db.count(), msgs.len() + 1);
+
+        // the id holding the key can keep writing it
+        let key1 = db.insert(Some(key1), fixture[1].clone())?;
+        assert!(db.update(&key1, Box::new(|msg: &TestMessage| msg.clone())).unwrap().is_ok());
+
+        // and deleting it frees the key up
+        db.delete(db.get(&key1.id()).unwrap().key)?;
+        db.insert(None, fixture[1].clone())?;
 
         Ok(())
     }
 
     #[test]
-    fn update() -> Result<()> {
+    fn live_views() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
-        let (mut db, msgs) = init_db(dd_rc.clone(), None, true)?;
-
-        let (key, msg) = msgs.get(0).unwrap();
+        let dd_rc = Arc::new(data_dir);
+        let fixture = msg_fixture();
 
-        let kind = match msg {
-            TestMessage::Of { val: _, kind } => *kind,
-            TestMessage::Empty { kind } => *kind,
-        };
-        let updated_val = match msg {
-            TestMessage::Of { val, kind: _ } => format!(
-                "updated {}",
-                val
-            ),
-            _ => "".to_string(),
+        let options = MudbOptions::<TestMessage> {
+            live_views: true,
+            ..MudbOptions::default()
         };
 
-        let op: Box<dyn FnOnce(&TestMessage) -> TestMessage> = {
-            let updated_val = updated_val.clone();
-            Box::new(move |_| TestMessage::Of {
-                val: updated_val,
-                kind: kind
-            })
-        };
+        let mut db = Mudb::open_with_options(dd_rc, "test.ndjson", options)?;
+        let key1 = db.insert(None, fixture[0].clone())?;
+        db.add_view(&KString::from_static("kind"), Box::new(MsgKindIndexer {}))?;
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)), vec![fixture[0].clone()]);
 
-        let idx = db.update(key, op)
-            .unwrap()
-            .unwrap();
+        let key2 = db.insert(None, fixture[1].clone())?;
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)).len(), 2);
 
-        assert_eq!(idx.clone(), key.incr());
+        let moved = TestMessage::Of {
+            kind: 2,
+            val: "moved".to_string(),
+        };
+        db.insert(Some(key1), moved.clone())?;
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)), vec![fixture[1].clone()]);
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(2)), vec![moved]);
 
-        let found = db.get(&idx.id()).unwrap();
-        assert_eq!(found.obj, Some(TestMessage::Of {
-            val: updated_val.clone(),
-            kind
-        }));
+        db.delete(key2)?;
+        assert!(db.find_by_view("kind", IndexKey::Num(1)).is_empty());
+
+        // a batch build afterwards agrees with the live index
+        db.build_views()?;
+        assert!(db.find_by_view("kind", IndexKey::Num(1)).is_empty());
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(2)).len(), 1);
 
         Ok(())
     }
 
     #[test]
-    fn filter() -> Result<()> {
-        let msgs = msg_fixture();
-        let msg1 = msgs.get(0).unwrap();
-        let msg2 = msgs.get(1).unwrap();
-
-        // basic filtering
-        let filt1: QueryRef<'_, TestMessage> = &val_filter("hello");
-        assert_eq!(filt1.matches(&msg1), true);
-        assert_eq!(filt1.matches(&msg2), false);
+    fn saved_views() -> Result<()> {
+        let (_tmp, data_dir) = data_dir()?;
+        let dd_rc = Arc::new(data_dir);
+        let kind = KString::from_static("kind");
 
-        let filt2: QueryRef<'_, TestMessage> = &val_filter("goodbye");
-        assert_eq!(filt2.matches(&msg1), false);
-        assert_eq!(filt2.matches(&msg2), true);
+        {
+            let (db, _msgs) = init_db(dd_rc.clone(), None, true)?;
+            db.save_views()?;
+        }
 
-        // negation
-        assert_eq!(!filt1.matches(&msg1), false);
-        assert_eq!(!filt2.matches(&msg1), true);
+        {
+            let mut db = Mudb::<TestMessage>::open(dd_rc.clone(), "test.ndjson")?;
+            db.add_view(&kind, Box::new(MsgKindIndexer {}))?;
+            assert_eq!(db.load_views()?, 1);
+            assert_eq!(db.find_by_view("kind", IndexKey::Num(1)).len(), 2);
 
-        // logical 'and'
-        assert_eq!((filt1 & filt2).matches(&msg1), false);
+            db.insert(None, TestMessage::Of {
+                kind: 1,
+                val: "one more".to_string(),
+            })?;
+        }
 
-        // logical 'or'
-        assert_eq!((filt1 | filt2).matches(&msg1), true);
+        // the saved view no longer matches the data, so it gets rebuilt
+        let mut db = Mudb::<TestMessage>::open(dd_rc, "test.ndjson")?;
+        db.add_view(&kind, Box::new(MsgKindIndexer {}))?;
+        assert_eq!(db.load_views()?, 0);
+        assert_eq!(db.find_by_view("kind", IndexKey::Num(1)).len(), 3);
 
         Ok(())
     }
 
     #[test]
-    fn find() -> Result<()> {
+    fn in_memory() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
-        let (db, msgs) = init_db(dd_rc, None, true)?;
+        let fixture = msg_fixture();
 
-        let filt: QueryRef<'_, TestMessage> = &val_filter("hello");
+        {
+            let mut db = Mudb::<TestMessage>::in_memory();
+            db.add_view(&KString::from_static("kind"), Box::new(MsgKindIndexer {}))?;
 
-        let (_key1, msg1) = msgs.get(0).unwrap();
-        let (_key2, msg2) = msgs.get(1).unwrap();
+            let mut keys = vec![];
+            for msg in &fixture {
+                keys.push(db.insert(None, msg.clone())?);
+            }
+            db.build_views()?;
 
-        let found = db.find(filt);
-        assert_eq!(found.len(), 1);
-        assert_eq!(found.get(0).unwrap(), &msg1.clone());
+            let (key1, msg1) = (&keys[0], &fixture[0]);
+            assert_eq!(db.get(&key1.id()).and_then(|doc| doc.obj), Some(msg1.clone()));
+            assert_eq!(db.find(&val_filter("hello")), vec![msg1.clone()]);
+            assert_eq!(db.find_by_view("kind", IndexKey::Num(1)).len(), 2);
 
-        let inverse = !filt;
-        let found = db.find(&inverse);
-        assert_eq!(found.len(), 2);
-        assert!(found.iter().find(|msg| msg.clone() == msg2).is_some());
+            assert_eq!(db.commit()?, fixture.len());
+            assert!(!db.modified());
+
+            db.delete(key1.clone())?;
+            db.compact()?;
+            assert!(!db.modified());
+            assert_eq!(db.count(), fixture.len());
+        }
+
+        assert_eq!(data_dir.entries()?.count(), 0);
 
         Ok(())
     }
 
     #[test]
-    fn views() -> Result<()> {
+    fn sync_mudb() -> Result<()> {
         let (_tmp, data_dir) = data_dir()?;
-        let dd_rc = Rc::new(data_dir);
+        let dd_rc = Arc::new(data_dir);
         let (db, msgs) = init_db(dd_rc, None, true)?;
 
-        let (_key1, msg1) = msgs.get(0).unwrap();
-        let (_key2, msg2) = msgs.get(1).unwrap();
-
-        let results = db.find_by_view(
-            &"kind".to_string(),
-            IndexKey::Num(1)
-        );
-
-        assert_eq!(results.len(), 2);
-
-        let expected = HashSet::<TestMessage>::from(
-            [msg1.clone(), msg2.clone()]
-        );
+        let (key1, msg1) = msgs.first().unwrap().clone();
+        let shared = SyncMudb::new(db);
 
-        let found = HashSet::<TestMessage>::from_iter(
-            results.iter().map(|msg| msg.clone())
-        );
+        let readers: Vec<_> = (0..4).map(|_| {
+            let shared = shared.clone();
+            let (key1, msg1) = (key1.clone(), msg1.clone());
 
-        assert_eq!(expected, found);
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let found = shared.get(&key1.id()).and_then(|doc| doc.obj);
+                    assert_eq!(found, Some(msg1.clone()));
+                    assert!(!shared.find(&val_filter("hello")).is_empty());
+                    assert_eq!(shared.find_by_view("kind", IndexKey::Num(1)).len(), 2);
+                }
+            })
+        }).collect();
 
-        let results = db.find_by_view(
-            &"kind".to_string(),
-            IndexKey::Num(2)
-        );
+        let writer = {
+            let shared = shared.clone();
 
-        assert_eq!(results.len(), 0);
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    shared.insert(None, TestMessage::Of {
+                        kind: 3,
+                        val: format!("threaded {}", i),
+                    })?;
+                }
+                shared.commit()?;
+                Ok(())
+            })
+        };
 
-        let results = db.find_by_view(
-            &"nonesuch".to_string(),
-            IndexKey::Num(1)
-        );
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        writer.join().unwrap()?;
 
-        assert_eq!(results.len(), 0);
+        assert_eq!(shared.read().count(), msgs.len() + 50);
+        assert!(!shared.read().modified());
 
         Ok(())
     }
@@ -852,12 +6623,12 @@ mod test {
     fn commit_on_drop() -> Result<()> {
         {
             let (_tmp, data_dir) = data_dir()?;
-            let dd_rc = Rc::new(data_dir);
+            let dd_rc = Arc::new(data_dir);
             let (mut db, msgs) = init_db(dd_rc, None, true)?;
 
             assert!(!db.modified());
 
-            let (key1, _) = msgs.get(0).unwrap();
+            let (key1, _) = msgs.first().unwrap();
 
             let _ = db.update(key1, Box::new(|msg: &TestMessage| {
                 TestMessage::Of {
@@ -871,7 +6642,7 @@ mod test {
 
         {
             let (_tmp, data_dir) = data_dir()?;
-            let dd_rc = Rc::new(data_dir);
+            let dd_rc = Arc::new(data_dir);
             let (db, _msgs) = init_db(dd_rc, None, false)?;
             assert!(!db.modified());
         }
@@ -0,0 +1,86 @@
+use crate::{MudbError, Result};
+use cap_std::fs::{Dir, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+/// An advisory `<filename>.lock` marking a data file as held by a writer.
+///
+/// The lock is taken by creating the file exclusively and released by
+/// removing it on drop. The lock file records the holder's pid, so a lock
+/// left behind by a process that died without dropping its handle is taken
+/// over by the next writer. Where liveness can't be checked (off unix), a
+/// stale lock still has to be removed by hand.
+pub(crate) struct LockFile {
+    data_dir: Arc<Dir>,
+    name: String,
+}
+
+impl LockFile {
+    pub(crate) fn acquire(data_dir: Arc<Dir>, filename: &str) -> Result<Self> {
+        let name = format!("{}.lock", filename);
+
+        let mut file = match create(&data_dir, &name) {
+            Err(MudbError::Locked(_)) if holder_gone(&data_dir, &name) => {
+                warn!(name, "taking over stale lock file");
+                data_dir.remove_file(&name)?;
+                create(&data_dir, &name)?
+            },
+            other => other?,
+        };
+
+        let lock = Self { data_dir, name };
+        writeln!(file, "{}", std::process::id())?;
+
+        Ok(lock)
+    }
+}
+
+fn create(data_dir: &Dir, name: &str) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    options.create_new(true);
+
+    match data_dir.open_with(name, &options) {
+        Ok(file) => Ok(file),
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            Err(MudbError::Locked(name.to_string()))
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Whether the lock file names a pid that's no longer running. A lock that
+// can't be read or holds no pid may belong to a writer still starting up,
+// so it's left alone.
+fn holder_gone(data_dir: &Dir, name: &str) -> bool {
+    data_dir.read_to_string(name)
+        .ok()
+        .and_then(|holder| holder.trim().parse::<u32>().ok())
+        .is_some_and(|pid| !pid_alive(pid))
+}
+
+#[cfg(unix)]
+fn pid_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return true;
+    };
+
+    // Signal 0 only checks that the process exists; EPERM means it does,
+    // but belongs to another user.
+    let found = unsafe { libc::kill(pid, 0) } == 0;
+    found || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+#[cfg(not(unix))]
+fn pid_alive(_pid: u32) -> bool {
+    true
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.data_dir.remove_file(&self.name) {
+            error!("failed to remove lock file {}: {:?}", self.name, err);
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// Ready-made queries over a field pulled out of each doc by an extractor.
+
+use crate::Query;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+use std::ops::{Bound, RangeBounds};
+#[cfg(feature = "regex")]
+use crate::{MudbError, Result};
+
+/// Picks the text a query looks at, or `None` to never match.
+pub type TextField<T> = fn(&T) -> Option<&str>;
+
+/// Matches docs whose field equals `value`.
+#[derive(Debug, Clone)]
+pub struct FieldEq<T, K> {
+    value: K,
+    field: fn(&T) -> &K,
+}
+
+impl <T, K> FieldEq<T, K> {
+    pub fn new(value: K, field: fn(&T) -> &K) -> Self {
+        Self { value, field }
+    }
+}
+
+impl <'a, T: fmt::Debug, K: PartialEq + fmt::Debug> Query<'a, T> for FieldEq<T, K> {
+    fn matches(&self, obj: &T) -> bool {
+        (self.field)(obj) == &self.value
+    }
+}
+
+/// Matches docs whose field contains `needle`, case and all.
+#[derive(Debug, Clone)]
+pub struct Contains<T> {
+    needle: String,
+    field: TextField<T>,
+}
+
+impl <T> Contains<T> {
+    pub fn new(needle: &str, field: TextField<T>) -> Self {
+        Self {
+            needle: needle.to_string(),
+            field,
+        }
+    }
+}
+
+impl <'a, T: fmt::Debug> Query<'a, T> for Contains<T> {
+    fn matches(&self, obj: &T) -> bool {
+        (self.field)(obj).is_some_and(|text| text.contains(&self.needle))
+    }
+}
+
+/// Matches docs whose field contains `needle`, ignoring case.
+#[derive(Debug, Clone)]
+pub struct ContainsIgnoreCase<T> {
+    needle: String,
+    field: TextField<T>,
+}
+
+impl <T> ContainsIgnoreCase<T> {
+    pub fn new(needle: &str, field: TextField<T>) -> Self {
+        Self {
+            needle: needle.to_lowercase(),
+            field,
+        }
+    }
+}
+
+impl <'a, T: fmt::Debug> Query<'a, T> for ContainsIgnoreCase<T> {
+    fn matches(&self, obj: &T) -> bool {
+        (self.field)(obj).is_some_and(|text| text.to_lowercase().contains(&self.needle))
+    }
+}
+
+/// Matches docs whose field falls within a range, e.g. `1..=2`.
+#[derive(Debug, Clone)]
+pub struct RangeQuery<T, K> {
+    bounds: (Bound<K>, Bound<K>),
+    field: fn(&T) -> K,
+}
+
+impl <T, K: Ord + Clone> RangeQuery<T, K> {
+    pub fn new(range: impl RangeBounds<K>, field: fn(&T) -> K) -> Self {
+        Self {
+            bounds: (range.start_bound().cloned(), range.end_bound().cloned()),
+            field,
+        }
+    }
+}
+
+impl <'a, T: fmt::Debug, K: Ord + fmt::Debug> Query<'a, T> for RangeQuery<T, K> {
+    fn matches(&self, obj: &T) -> bool {
+        self.bounds.contains(&(self.field)(obj))
+    }
+}
+
+/// Matches docs whose field is one of a set of values, e.g. a kind in
+/// `{1, 3, 5}`.
+#[derive(Debug, Clone)]
+pub struct InQuery<T, K> {
+    values: HashSet<K>,
+    field: fn(&T) -> K,
+}
+
+impl <T, K: Eq + Hash> InQuery<T, K> {
+    pub fn new(values: impl IntoIterator<Item = K>, field: fn(&T) -> K) -> Self {
+        Self {
+            values: values.into_iter().collect(),
+            field,
+        }
+    }
+}
+
+impl <'a, T: fmt::Debug, K: Eq + Hash + fmt::Debug> Query<'a, T> for InQuery<T, K> {
+    fn matches(&self, obj: &T) -> bool {
+        self.values.contains(&(self.field)(obj))
+    }
+}
+
+/// Matches docs whose field matches a regex, compiled once up front.
+#[cfg(feature = "regex")]
+#[derive(Debug, Clone)]
+pub struct RegexQuery<T> {
+    regex: regex::Regex,
+    field: TextField<T>,
+}
+
+#[cfg(feature = "regex")]
+impl <T> RegexQuery<T> {
+    /// Fails with `MudbError::Invalid` if `pattern` doesn't compile.
+    pub fn new(pattern: &str, field: TextField<T>) -> Result<Self> {
+        let regex = regex::Regex::new(pattern)
+            .map_err(|err| MudbError::Invalid(err.to_string()))?;
+
+        Ok(Self { regex, field })
+    }
+}
+
+#[cfg(feature = "regex")]
+impl <'a, T: fmt::Debug> Query<'a, T> for RegexQuery<T> {
+    fn matches(&self, obj: &T) -> bool {
+        (self.field)(obj).is_some_and(|text| self.regex.is_match(text))
+    }
+}
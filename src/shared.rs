@@ -0,0 +1,57 @@
+use crate::{Doc, DocType, IndexKey, Mudb, Query, Result, VersionedKey};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A cloneable handle to a [`Mudb`] that can be shared across threads.
+///
+/// Every handle points at the same db behind a single `RwLock`: any number
+/// of threads can read at once, but a write (including `commit`, which does
+/// file IO while holding the lock) blocks all readers until it finishes.
+/// Single-threaded callers should keep using `Mudb` directly and skip the
+/// locking overhead.
+pub struct SyncMudb<T: DocType> {
+    inner: Arc<RwLock<Mudb<T>>>,
+}
+
+impl <T: DocType> SyncMudb<T> {
+    pub fn new(db: Mudb<T>) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(db)),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Mudb<T>> {
+        self.inner.read().expect("mudb lock poisoned")
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Mudb<T>> {
+        self.inner.write().expect("mudb lock poisoned")
+    }
+
+    pub fn get(&self, id: &IndexKey) -> Option<Doc<T>> {
+        self.read().get(id)
+    }
+
+    pub fn find(&self, filter: &dyn for<'a> Query<'a, T>) -> Vec<T> {
+        self.read().find(filter)
+    }
+
+    pub fn find_by_view(&self, name: &str, lookup_key: IndexKey) -> Vec<T> {
+        self.read().find_by_view(name, lookup_key)
+    }
+
+    pub fn insert(&self, key: Option<VersionedKey>, obj: T) -> Result<VersionedKey> {
+        self.write().insert(key, obj)
+    }
+
+    pub fn commit(&self) -> Result<usize> {
+        self.write().commit()
+    }
+}
+
+impl <T: DocType> Clone for SyncMudb<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use crate::{latest_version, newest_versions, Doc, DocType, Flag, IndexKey, QueryRef, VersionedKey};
+use im::OrdMap;
+
+/// A point-in-time view of a `Mudb`, taken by `Mudb::snapshot`.
+///
+/// Taking one shares structure with the db's map rather than copying it, and
+/// later writes to the db don't show up in it.
+#[derive(Debug, Clone)]
+pub struct ReadSnapshot<T: DocType> {
+    data: OrdMap<VersionedKey, Doc<T>>,
+}
+
+impl <T: DocType> ReadSnapshot<T> {
+    pub(crate) fn new(data: OrdMap<VersionedKey, Doc<T>>) -> Self {
+        Self { data }
+    }
+
+    /// The newest version of `id` as of the snapshot, unless it was deleted.
+    pub fn get(&self, id: &IndexKey) -> Option<Doc<T>> {
+        latest_version(&self.data, id)
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .cloned()
+    }
+
+    /// The live docs matching `filter`, in id order.
+    pub fn find<'a>(&'a self, filter: QueryRef<'a, T>) -> Vec<T> {
+        self.iter()
+            .map(|(_key, obj)| obj)
+            .filter(|obj| filter.matches(obj))
+            .cloned()
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (VersionedKey, &T)> {
+        newest_versions(self.data.values())
+            .filter(|doc| !doc.has_flag(&Flag::Deleted))
+            .flat_map(|doc| doc.obj.as_ref().map(|obj| (doc.key.clone(), obj)))
+    }
+
+    /// How many ids had a live newest version when the snapshot was taken.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+}
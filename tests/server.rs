@@ -0,0 +1,177 @@
+use cap_std::ambient_authority;
+use cap_std::fs::Dir;
+use mudb::JsonMudb;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+
+// A fresh directory under cargo's scratch space for each test.
+fn data_dir(name: &str) -> anyhow::Result<(PathBuf, Arc<Dir>)> {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path)?;
+
+    let dir = Dir::open_ambient_dir(&path, ambient_authority())?;
+    Ok((path, Arc::new(dir)))
+}
+
+fn server(data_dir: &PathBuf, args: &[&str]) -> anyhow::Result<Output> {
+    Ok(Command::new(env!("CARGO_BIN_EXE_mudb-server"))
+        .arg("--data-dir")
+        .arg(data_dir)
+        .args(args)
+        .output()?)
+}
+
+#[test]
+fn dump() -> anyhow::Result<()> {
+    let (path, dir) = data_dir("dump")?;
+
+    {
+        let mut db = JsonMudb::open(dir, "test.ndjson")?;
+        db.insert(None, json!({ "msg": "hello" }))?;
+        let gone = db.insert(None, json!({ "msg": "deleted" }))?;
+        db.delete(gone)?;
+        db.insert(None, json!({ "msg": "goodbye" }))?;
+    }
+
+    let output = server(&path, &["dump", "test.ndjson"])?;
+    assert!(output.status.success());
+
+    let mut msgs: Vec<Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(|line| Ok(serde_json::from_str::<Value>(line)?["obj"]["msg"].clone()))
+        .collect::<anyhow::Result<_>>()?;
+    msgs.sort_by_key(|msg| msg.to_string());
+    assert_eq!(msgs, vec![json!("goodbye"), json!("hello")]);
+
+    let output = server(&path, &["dump", "missing.ndjson"])?;
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)?.starts_with("mudb-server: "));
+
+    Ok(())
+}
+
+#[test]
+fn compact() -> anyhow::Result<()> {
+    let (path, dir) = data_dir("compact")?;
+
+    {
+        let mut db = JsonMudb::open(dir.clone(), "test.ndjson")?;
+        for i in 0..10 {
+            let key = db.insert(None, json!({ "n": i }))?;
+            db.commit()?;
+            if i % 2 == 0 {
+                db.delete(key)?;
+                db.commit()?;
+            }
+        }
+    }
+
+    let len = |file: &str| -> anyhow::Result<u64> { Ok(dir.metadata(file)?.len()) };
+    let before = len("test.ndjson")?;
+
+    let output = server(&path, &["compact", "test.ndjson"])?;
+    assert!(output.status.success());
+    let after = len("test.ndjson")?;
+    assert!(after < before);
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!("reclaimed {} bytes\n", before - after)
+    );
+
+    let output = server(&path, &["compact", "--drop-tombstones", "test.ndjson"])?;
+    assert!(output.status.success());
+    assert!(len("test.ndjson")? < after);
+    assert_eq!(JsonMudb::open_read_only(dir.clone(), "test.ndjson")?.count(), 5);
+
+    let output = server(&path, &["compact", "missing.ndjson"])?;
+    assert!(!output.status.success());
+    assert!(dir.metadata("missing.ndjson").is_err());
+
+    dir.write("corrupt.ndjson", "{\"key\": nope}\n")?;
+    let output = server(&path, &["compact", "corrupt.ndjson"])?;
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        "mudb-server: failed to deserialize record at line 1\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn check() -> anyhow::Result<()> {
+    let (path, dir) = data_dir("check")?;
+
+    {
+        let mut db = JsonMudb::open(dir.clone(), "good.ndjson")?;
+        for i in 0..3 {
+            db.insert(None, json!({ "n": i }))?;
+        }
+    }
+
+    let output = server(&path, &["check", "good.ndjson"])?;
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "3 valid records\n");
+
+    let mut corrupt = dir.read("good.ndjson")?;
+    corrupt.extend_from_slice(b"{\"key\": nope}\n");
+    corrupt.extend_from_slice(&dir.read("good.ndjson")?);
+    corrupt.extend_from_slice(b"not json\n");
+    dir.write("corrupt.ndjson", corrupt)?;
+
+    let output = server(&path, &["check", "corrupt.ndjson"])?;
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stdout)?, "6 valid records\n");
+    assert_eq!(
+        String::from_utf8(output.stderr)?,
+        "mudb-server: invalid doc: 2 corrupt records, the first at line 4\n"
+    );
+
+    let output = server(&path, &["check", "missing.ndjson"])?;
+    assert!(!output.status.success());
+
+    Ok(())
+}
+
+#[test]
+fn repl() -> anyhow::Result<()> {
+    let (path, dir) = data_dir("repl")?;
+
+    {
+        let mut db = JsonMudb::open(dir, "test.ndjson")?;
+        db.insert(Some(mudb::VersionedKey::new(mudb::IndexKey::Num(1))), json!({ "msg": "hello" }))?;
+        db.insert(Some(mudb::VersionedKey::new(mudb::IndexKey::Num(2))), json!({ "msg": "goodbye" }))?;
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mudb-server"))
+        .arg("--data-dir")
+        .arg(&path)
+        .args(["repl", "test.ndjson"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    child.stdin.take().unwrap().write_all(b"count\nget 2\nget 3\nfind hell\nrecent 1\nbogus\n")?;
+    let output = child.wait_with_output()?;
+    assert!(output.status.success());
+
+    let lines: Vec<Value> = String::from_utf8(output.stdout)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert_eq!(lines, vec![
+        json!(2),
+        json!({ "msg": "goodbye" }),
+        Value::Null,
+        json!([{ "msg": "hello" }]),
+        json!([{ "msg": "goodbye" }]),
+    ]);
+    assert_eq!(String::from_utf8(output.stderr)?, "unknown command: \"bogus\"\n");
+
+    Ok(())
+}